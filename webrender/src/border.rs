@@ -29,7 +29,7 @@ pub enum BorderCornerSide {
 }
 
 #[repr(C)]
-enum BorderCorner {
+pub enum BorderCorner {
     TopLeft,
     TopRight,
     BottomLeft,
@@ -50,7 +50,7 @@ pub enum BorderCornerKind {
 }
 
 impl BorderCornerKind {
-    fn new_mask(
+    pub fn new_mask(
         kind: BorderCornerClipKind,
         width0: f32,
         width1: f32,
@@ -609,6 +609,12 @@ impl BorderCornerClipSource {
         }
     }
 
+    /// The local space rect of the corner. Nothing outside of it
+    /// is affected by the clip.
+    pub fn corner_rect(&self) -> LayerRect {
+        self.corner_data.corner_rect
+    }
+
     pub fn write(&mut self, mut request: GpuDataRequest) {
         self.corner_data.write(&mut request);
 
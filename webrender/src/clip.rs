@@ -2,15 +2,24 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use api::{BorderRadius, ClipMode, ComplexClipRegion, DeviceIntRect, ImageMask, ImageRendering};
-use api::{LayerPoint, LayerRect, LayerToWorldTransform, LayoutPoint, LayoutVector2D, LocalClip};
+use api::{BorderRadius, ClipMode, ComplexClipRegion, DeviceIntRect, ImageKey};
+use api::{ImageMask, ImageRendering, LayerPoint, LayerRect, LayerSize};
+use api::{LayerToWorldTransform, LayoutPoint, LayoutVector2D, LocalClip};
 use border::BorderCornerClipSource;
+use device::FrameId;
 use ellipse::Ellipse;
 use freelist::{FreeList, FreeListHandle, WeakFreeListHandle};
 use gpu_cache::{GpuCache, GpuCacheHandle, ToGpuBlocks};
+use internal_types::FastHashMap;
 use prim_store::{ClipData, ImageMaskData};
 use resource_cache::ResourceCache;
-use util::{MaxRect, calculate_screen_bounding_rect, extract_inner_rect_safe};
+use std::slice;
+use util::{MatrixHelpers, MaxRect, SnapMode, TransformedRect};
+use util::calculate_screen_bounding_rect_snapped;
+use util::{extract_inner_rect_safe, rect_is_empty};
+
+/// The maximum number of holes of a `ClipSource::RectWithHoles`.
+pub const MAX_CLIP_HOLES: usize = 8;
 
 pub type ClipStore = FreeList<ClipSources>;
 pub type ClipSourcesHandle = FreeListHandle<ClipSources>;
@@ -30,14 +39,16 @@ impl ClipRegion {
         mut image_mask: Option<ImageMask>,
         reference_frame_relative_offset: &LayoutVector2D,
     ) -> ClipRegion {
-        let rect = rect.translate(reference_frame_relative_offset);
+        let rect = clamp_to_non_negative_size(&rect).translate(reference_frame_relative_offset);
 
         if let Some(ref mut image_mask) = image_mask {
-            image_mask.rect = image_mask.rect.translate(reference_frame_relative_offset);
+            image_mask.rect = clamp_to_non_negative_size(&image_mask.rect)
+                .translate(reference_frame_relative_offset);
         }
 
         for complex_clip in complex_clips.iter_mut() {
-            complex_clip.rect = complex_clip.rect.translate(reference_frame_relative_offset);
+            complex_clip.rect = clamp_to_non_negative_size(&complex_clip.rect)
+                .translate(reference_frame_relative_offset);
         }
 
         ClipRegion {
@@ -64,11 +75,129 @@ impl ClipRegion {
     }
 }
 
-#[derive(Debug)]
+// Bad layout can hand us rectangles with a negative width or height, which
+// confuses the intersection code further down. Treat those as empty instead.
+fn clamp_to_non_negative_size(rect: &LayerRect) -> LayerRect {
+    LayerRect::new(
+        rect.origin,
+        LayerSize::new(rect.size.width.max(0.0), rect.size.height.max(0.0)),
+    )
+}
+
+// Radii larger than half the rect's smallest dimension overlap each other, and
+// leave no room for an inner rect. Clamp them so that the inner rect of a
+// non-empty rounded rect is always known.
+fn clamp_radii(rect: &LayerRect, radii: &BorderRadius) -> BorderRadius {
+    let max_radius = 0.5 * rect.size.width.min(rect.size.height);
+    let clamp = |size: LayerSize| {
+        LayerSize::new(size.width.min(max_radius), size.height.min(max_radius))
+    };
+
+    BorderRadius {
+        top_left: clamp(radii.top_left),
+        top_right: clamp(radii.top_right),
+        bottom_left: clamp(radii.bottom_left),
+        bottom_right: clamp(radii.bottom_right),
+    }
+}
+
+/// Whether the rounded rect `inner_rect` lies entirely inside the rounded
+/// rect `outer_rect`. This is conservative, it may return false for some
+/// rounded rects that are contained, but never the other way around.
+pub fn rounded_contains_rounded(
+    outer_rect: &LayerRect,
+    outer_radii: &BorderRadius,
+    inner_rect: &LayerRect,
+    inner_radii: &BorderRadius,
+) -> bool {
+    if !outer_rect.contains_rect(inner_rect) {
+        return false;
+    }
+
+    // The distances of the inner rect from each corner of the outer rect.
+    let left = inner_rect.min_x() - outer_rect.min_x();
+    let right = outer_rect.max_x() - inner_rect.max_x();
+    let top = inner_rect.min_y() - outer_rect.min_y();
+    let bottom = outer_rect.max_y() - inner_rect.max_y();
+
+    corner_contains_corner(outer_radii.top_left, inner_radii.top_left, left, top) &&
+        corner_contains_corner(outer_radii.top_right, inner_radii.top_right, right, top) &&
+        corner_contains_corner(outer_radii.bottom_left, inner_radii.bottom_left, left, bottom) &&
+        corner_contains_corner(outer_radii.bottom_right, inner_radii.bottom_right, right, bottom)
+}
+
+// Whether a rounded corner with radius `inner`, at a distance of `dx` and `dy`
+// from the corner of the rect of a rounded corner with radius `outer`, is
+// inside of it.
+fn corner_contains_corner(outer: LayerSize, inner: LayerSize, dx: f32, dy: f32) -> bool {
+    if outer.width <= 0.0 || outer.height <= 0.0 {
+        return true;
+    }
+
+    // Scaled by the outer radius, the outer corner is a circle of radius one.
+    let x = dx / outer.width;
+    let y = dy / outer.height;
+    if x >= 1.0 || y >= 1.0 {
+        // The inner rect doesn't reach into the rounded part.
+        return true;
+    }
+
+    // The inner corner is inside of a circle around its center, with the
+    // larger of its scaled radii. The rest of the inner rect is closer to
+    // the center of the outer corner.
+    let a = inner.width / outer.width;
+    let b = inner.height / outer.height;
+    let cx = 1.0 - (x + a);
+    let cy = 1.0 - (y + b);
+    (cx * cx + cy * cy).sqrt() + a.max(b) <= 1.0
+}
+
+// The shape of a rounded rect clip that can be compared with others using
+// `rounded_contains_rounded`.
+fn plain_rounded_rect(clip: &ClipSource) -> Option<(&LayerRect, &BorderRadius)> {
+    match *clip {
+        ClipSource::RoundedRectangle(ref rect, ref radii, ClipMode::Clip) => Some((rect, radii)),
+        _ => None,
+    }
+}
+
+// Which of the rounded rect clips contain another rounded rect clip, since
+// they don't clip anything that the other doesn't. Of two equal clips, the
+// first one is redundant.
+fn redundant_rounded_clips(clips: &[(ClipSource, GpuCacheHandle)]) -> Vec<bool> {
+    let mut redundant = vec![false; clips.len()];
+    for index in 0 .. clips.len() {
+        let (outer_rect, outer_radii) = match plain_rounded_rect(&clips[index].0) {
+            Some(shape) => shape,
+            None => continue,
+        };
+        let is_redundant = (0 .. clips.len()).any(|other| {
+            if other == index || redundant[other] {
+                return false;
+            }
+            match plain_rounded_rect(&clips[other].0) {
+                Some((inner_rect, inner_radii)) => {
+                    rounded_contains_rounded(outer_rect, outer_radii, inner_rect, inner_radii)
+                }
+                None => false,
+            }
+        });
+        redundant[index] = is_redundant;
+    }
+    redundant
+}
+
+#[derive(Clone, Debug)]
 pub enum ClipSource {
     Rectangle(LayerRect),
     RoundedRectangle(LayerRect, BorderRadius, ClipMode),
     Image(ImageMask),
+    /// A rectangle with rectangular holes cut out of it. Only the first
+    /// `MAX_CLIP_HOLES` holes are cut out, any further holes are ignored.
+    RectWithHoles {
+        rect: LayerRect,
+        holes: Vec<LayerRect>,
+    },
     /// TODO(gw): This currently only handles dashed style
     /// clips, where the border style is dashed for both
     /// adjacent border edges. Expand to handle dotted style
@@ -76,17 +205,60 @@ pub enum ClipSource {
     BorderCorner(BorderCornerClipSource),
 }
 
+/// The type of a clip source, without its parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClipKind {
+    Rectangle,
+    RoundedRectangle,
+    Image,
+    RectWithHoles,
+    BorderCorner,
+}
+
+impl ClipKind {
+    // Clips drawn by the same shader get the same rank, see `reorder_for_gpu`.
+    fn gpu_rank(&self) -> u8 {
+        match *self {
+            ClipKind::Rectangle | ClipKind::RectWithHoles => 0,
+            ClipKind::RoundedRectangle => 1,
+            ClipKind::Image => 2,
+            ClipKind::BorderCorner => 3,
+        }
+    }
+}
+
 impl From<ClipRegion> for ClipSources {
     fn from(region: ClipRegion) -> ClipSources {
+        if region.image_mask.is_none() && region.complex_clips.is_empty() {
+            return ClipSources::new_rectangle(region.main);
+        }
+
         let mut clips = Vec::new();
 
         if let Some(info) = region.image_mask {
             clips.push(ClipSource::Image(info));
         }
 
-        clips.push(ClipSource::Rectangle(region.main));
-
+        // Rectangular clip-outs are cut out of the main rect as holes, which
+        // keeps the outer bounds of the region known.
+        let mut holes = Vec::new();
+        let mut complex_clips = Vec::new();
         for complex in region.complex_clips {
+            if complex.mode == ClipMode::ClipOut && complex.radii.is_zero() &&
+                holes.len() < MAX_CLIP_HOLES {
+                holes.push(complex.rect);
+            } else {
+                complex_clips.push(complex);
+            }
+        }
+
+        if holes.is_empty() {
+            clips.push(ClipSource::Rectangle(region.main));
+        } else {
+            clips.push(ClipSource::RectWithHoles { rect: region.main, holes });
+        }
+
+        for complex in complex_clips {
             clips.push(ClipSource::RoundedRectangle(
                 complex.rect,
                 complex.radii,
@@ -94,65 +266,399 @@ impl From<ClipRegion> for ClipSources {
             ));
         }
 
-        ClipSources::new(clips)
+        let mut clip_sources = ClipSources::new(clips);
+        clip_sources.reorder_for_gpu();
+        clip_sources
+    }
+}
+
+// Replace a rect with non-finite coordinates, e.g. from a degenerate
+// transform, by an empty one. Returns whether the rect was replaced.
+fn sanitize_rect(rect: &mut LayerRect) -> bool {
+    let finite = rect.origin.x.is_finite() && rect.origin.y.is_finite() &&
+        rect.size.width.is_finite() && rect.size.height.is_finite();
+    if !finite {
+        *rect = LayerRect::zero();
     }
+    !finite
+}
+
+fn sanitize_radii(radii: &mut BorderRadius) -> bool {
+    let finite = [radii.top_left, radii.top_right, radii.bottom_left, radii.bottom_right]
+        .iter()
+        .all(|size| size.width.is_finite() && size.height.is_finite());
+    if !finite {
+        *radii = BorderRadius::zero();
+    }
+    !finite
 }
 
 impl ClipSource {
+    // Make sure that the geometry of this clip is finite, since a single
+    // NaN would spread to the bounds of the whole clip chain. Returns
+    // whether anything had to be replaced.
+    fn sanitize(&mut self) -> bool {
+        match *self {
+            ClipSource::Rectangle(ref mut rect) => sanitize_rect(rect),
+            ClipSource::RoundedRectangle(ref mut rect, ref mut radii, _) => {
+                sanitize_rect(rect) | sanitize_radii(radii)
+            }
+            ClipSource::Image(ref mut mask) => sanitize_rect(&mut mask.rect),
+            ClipSource::RectWithHoles { ref mut rect, ref mut holes } => {
+                holes.iter_mut().fold(sanitize_rect(rect), |sanitized, hole| {
+                    sanitize_rect(hole) | sanitized
+                })
+            }
+            ClipSource::BorderCorner(..) => false,
+        }
+    }
+
     pub fn contains(&self, point: &LayerPoint) -> bool {
         // We currently do not handle all BorderCorners, because they aren't used for
         // ClipScrollNodes and this method is only used during hit testing.
         match self {
             &ClipSource::Rectangle(ref rectangle) => rectangle.contains(point),
-            &ClipSource::RoundedRectangle(rect, radii, ClipMode::Clip) =>
-                rounded_rectangle_contains_point(point, &rect, &radii),
-            &ClipSource::RoundedRectangle(rect, radii, ClipMode::ClipOut) =>
-                !rounded_rectangle_contains_point(point, &rect, &radii),
+            &ClipSource::RoundedRectangle(rect, radii, mode) => {
+                match mode {
+                    ClipMode::Clip => rounded_rectangle_contains_point(point, &rect, &radii),
+                    ClipMode::ClipOut => !rounded_rectangle_contains_point(point, &rect, &radii),
+                }
+            }
             &ClipSource::Image(mask) => mask.rect.contains(point),
+            &ClipSource::RectWithHoles { ref rect, ref holes } => {
+                rect.contains(point) &&
+                    !holes.iter().take(MAX_CLIP_HOLES).any(|hole| hole.contains(point))
+            }
             &ClipSource::BorderCorner(_) =>
                 unreachable!("Tried to call contains on a BorderCornerr."),
         }
     }
 
+    pub fn kind(&self) -> ClipKind {
+        match *self {
+            ClipSource::Rectangle(..) => ClipKind::Rectangle,
+            ClipSource::RoundedRectangle(..) => ClipKind::RoundedRectangle,
+            ClipSource::Image(..) => ClipKind::Image,
+            ClipSource::RectWithHoles { .. } => ClipKind::RectWithHoles,
+            ClipSource::BorderCorner(..) => ClipKind::BorderCorner,
+        }
+    }
+
+    /// The local rectangle covered by the geometry of this clip, ignoring
+    /// its mode.
+    pub fn local_rect(&self) -> LayerRect {
+        match *self {
+            ClipSource::Rectangle(rect) => rect,
+            ClipSource::RoundedRectangle(rect, ..) => rect,
+            ClipSource::Image(ref mask) => mask.rect,
+            ClipSource::RectWithHoles { rect, .. } => rect,
+            ClipSource::BorderCorner(ref source) => source.corner_rect(),
+        }
+    }
+}
+
+/// What is known about the outer bounds of a clip. An empty clip clips
+/// out everything, while nothing can be culled against unknown bounds
+/// (e.g. for clip-outs).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BoundsKind {
+    Known(LayerRect),
+    Empty,
+    Unknown,
 }
 
+impl BoundsKind {
+    fn from_rect(rect: Option<LayerRect>) -> BoundsKind {
+        match rect {
+            Some(rect) if !rect_is_empty(&rect) => BoundsKind::Known(rect),
+            _ => BoundsKind::Empty,
+        }
+    }
+}
+
+/// The number of transforms `ClipSources` remembers the device bounds for.
+const DEVICE_BOUNDS_CACHE_SIZE: usize = 4;
+
+// Transforms aren't hashable, so key the device bounds on the raw bits of
+// the transform and the device pixel ratio.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DeviceBoundsKey([u32; 17]);
+
+impl DeviceBoundsKey {
+    fn new(transform: &LayerToWorldTransform, device_pixel_ratio: f32) -> DeviceBoundsKey {
+        let mut bits = [0; 17];
+        for (bits, value) in bits.iter_mut().zip(transform.to_row_major_array().iter()) {
+            *bits = value.to_bits();
+        }
+        bits[16] = device_pixel_ratio.to_bits();
+        DeviceBoundsKey(bits)
+    }
+}
+
+/// The device bounds of a clip under the last few transforms it was drawn
+/// with, so that a clip drawn several times per frame (e.g. in repeated
+/// instances of the same content) doesn't compute them over and over.
+#[derive(Debug)]
+struct DeviceBoundsCache {
+    // Least recently used first.
+    entries: Vec<(DeviceBoundsKey, (DeviceIntRect, Option<DeviceIntRect>))>,
+}
+
+impl DeviceBoundsCache {
+    fn new() -> DeviceBoundsCache {
+        DeviceBoundsCache {
+            entries: Vec::new(),
+        }
+    }
+
+    fn get(&mut self, key: &DeviceBoundsKey) -> Option<(DeviceIntRect, Option<DeviceIntRect>)> {
+        let index = match self.entries.iter().position(|&(ref k, _)| k == key) {
+            Some(index) => index,
+            None => return None,
+        };
+        let entry = self.entries.remove(index);
+        self.entries.push(entry);
+        Some(entry.1)
+    }
+
+    fn insert(&mut self, key: DeviceBoundsKey, bounds: (DeviceIntRect, Option<DeviceIntRect>)) {
+        if self.entries.len() == DEVICE_BOUNDS_CACHE_SIZE {
+            self.entries.remove(0);
+        }
+        self.entries.push((key, bounds));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+// Clip sources aren't serialized; captures record the display list they are
+// built from instead. If that changes, the serialized form needs a version
+// tag that is checked on load, since the clip source layout isn't stable
+// across versions.
 #[derive(Debug)]
 pub struct ClipSources {
     pub clips: Vec<(ClipSource, GpuCacheHandle)>,
+    // The clips that aren't redundant, which are the ones that are uploaded
+    // and drawn.
+    active: Vec<bool>,
+    // Whether each of the clips had its image mask requested by the last
+    // update. Only ever set for active image clips.
+    requested: Vec<bool>,
+    /// The part of the local space that isn't affected by the clips. It's
+    /// either empty, or contained in the outer rect if that is known.
     pub local_inner_rect: LayerRect,
-    pub local_outer_rect: Option<LayerRect>
+    pub local_outer_rect: BoundsKind,
+    // Whether the mask exceeded the texel budget of the last update.
+    over_budget: bool,
+    // Whether skipping the mask was logged, which is only done once.
+    reported_over_budget: bool,
+    is_masking: bool,
+    last_update_frame: Option<FrameId>,
+    device_bounds_cache: DeviceBoundsCache,
+    // The local rect the bounds are limited to, if any.
+    bounds_seed: Option<LayerRect>,
+}
+
+pub struct EnabledClips<'a> {
+    clips: slice::Iter<'a, (ClipSource, GpuCacheHandle)>,
+    enabled: slice::Iter<'a, bool>,
+}
+
+impl<'a> Iterator for EnabledClips<'a> {
+    type Item = &'a (ClipSource, GpuCacheHandle);
+
+    fn next(&mut self) -> Option<&'a (ClipSource, GpuCacheHandle)> {
+        while let (Some(clip), Some(&enabled)) = (self.clips.next(), self.enabled.next()) {
+            if enabled {
+                return Some(clip);
+            }
+        }
+        None
+    }
 }
 
 impl ClipSources {
+    /// Rounded rect clips that contain another one of `clips` are skipped
+    /// when building the masks and bounds.
     pub fn new(clips: Vec<ClipSource>) -> ClipSources {
-        let (local_inner_rect, local_outer_rect) = Self::calculate_inner_and_outer_rects(&clips);
-
+        let active = vec![true; clips.len()];
+        let requested = vec![false; clips.len()];
         let clips = clips
             .into_iter()
             .map(|clip| (clip, GpuCacheHandle::new()))
             .collect();
 
-        ClipSources {
+        let mut clip_sources = ClipSources {
             clips,
-            local_inner_rect,
-            local_outer_rect,
+            active,
+            requested,
+            local_inner_rect: LayerRect::zero(),
+            local_outer_rect: BoundsKind::Unknown,
+            over_budget: false,
+            reported_over_budget: false,
+            is_masking: false,
+            last_update_frame: None,
+            device_bounds_cache: DeviceBoundsCache::new(),
+            bounds_seed: None,
+        };
+        clip_sources.update_bounds();
+        clip_sources
+    }
+
+    /// A clip consisting of a single rectangle. The bounds are trivially
+    /// known, and no mask is needed to apply it in its own coordinate system.
+    pub fn new_rectangle(rect: LayerRect) -> ClipSources {
+        ClipSources {
+            clips: vec![(ClipSource::Rectangle(rect), GpuCacheHandle::new())],
+            active: vec![true],
+            requested: vec![false],
+            local_inner_rect: rect,
+            local_outer_rect: BoundsKind::from_rect(Some(rect)),
+            over_budget: false,
+            reported_over_budget: false,
+            is_masking: false,
+            last_update_frame: None,
+            device_bounds_cache: DeviceBoundsCache::new(),
+            bounds_seed: None,
         }
     }
 
+    /// A chain without any clips. It never masks, its bounds are unknown,
+    /// and updating it is a no-op.
+    pub fn empty() -> ClipSources {
+        ClipSources::new(Vec::new())
+    }
+
     pub fn clips(&self) -> &[(ClipSource, GpuCacheHandle)] {
         &self.clips
     }
 
-    fn calculate_inner_and_outer_rects(clips: &Vec<ClipSource>) -> (LayerRect, Option<LayerRect>) {
-        if clips.is_empty() {
-            return (LayerRect::zero(), None);
+    /// The clips that currently take part in clipping.
+    pub fn enabled_clips(&self) -> EnabledClips {
+        EnabledClips {
+            clips: self.clips.iter(),
+            enabled: self.active.iter(),
+        }
+    }
+
+    /// Group the clips by the shader that draws them, i.e. rectangles first,
+    /// then rounded rectangles, image masks and border corners, keeping the
+    /// order within each group. The clips all multiply into the same mask,
+    /// so this only changes the indices of the clips, not the result.
+    pub fn reorder_for_gpu(&mut self) {
+        let mut order: Vec<usize> = (0 .. self.clips.len()).collect();
+        order.sort_by_key(|&index| self.clips[index].0.kind().gpu_rank());
+        if order.iter().enumerate().all(|(position, &index)| position == index) {
+            return;
+        }
+
+        let mut clips: Vec<_> = self.clips.drain(..).map(Some).collect();
+        self.clips = order.iter().map(|&index| clips[index].take().unwrap()).collect();
+        let requested = order.iter().map(|&index| self.requested[index]).collect();
+        self.requested = requested;
+
+        // The bounds don't depend on the order, but which of two equal
+        // rounded clips is redundant does.
+        self.update_bounds();
+        self.invalidate_rectangles();
+        self.last_update_frame = None;
+    }
+
+    // The data uploaded for rectangles depends on the other clips, see
+    // `combined_rect`.
+    fn invalidate_rectangles(&mut self) {
+        for &mut (ref clip, ref mut handle) in &mut self.clips {
+            if let ClipSource::Rectangle(..) = *clip {
+                *handle = GpuCacheHandle::new();
+            }
+        }
+    }
+
+    /// Limit the bounds of this clip to `seed_rect`, e.g. the local rect of
+    /// the only primitive it applies to, or remove the limit with `None`.
+    /// Nothing outside of the seed rect is considered, so the outer bounds
+    /// are known (and tighter) even for clips that would otherwise have
+    /// unknown bounds.
+    pub fn update_bounds_with_seed(&mut self, seed_rect: Option<LayerRect>) {
+        self.bounds_seed = seed_rect;
+        self.update_bounds();
+    }
+
+    fn update_bounds(&mut self) {
+        let sanitized = self.clips
+            .iter_mut()
+            .fold(false, |sanitized, &mut (ref mut clip, _)| clip.sanitize() | sanitized);
+        if sanitized {
+            warn!("Replaced clips with non-finite geometry by empty ones");
+        }
+
+        let redundant = redundant_rounded_clips(&self.clips);
+        self.active = redundant.into_iter().map(|redundant| !redundant).collect();
+
+        let (local_inner_rect, local_outer_rect) = Self::calculate_inner_and_outer_rects(
+            self.enabled_clips().map(|&(ref clip, _)| clip),
+            self.bounds_seed,
+        );
+        let is_masking = self.enabled_clips().any(|&(ref clip, _)| match *clip {
+            ClipSource::Rectangle(..) => false,
+            _ => true,
+        });
+
+        self.local_inner_rect = local_inner_rect;
+        self.local_outer_rect = local_outer_rect;
+        self.is_masking = is_masking;
+        self.device_bounds_cache.clear();
+
+        if let BoundsKind::Known(ref outer_rect) = self.local_outer_rect {
+            debug_assert!(
+                rect_is_empty(&self.local_inner_rect) ||
+                    outer_rect.contains_rect(&self.local_inner_rect),
+                "Local inner rect {:?} is not inside the outer rect {:?}",
+                self.local_inner_rect,
+                outer_rect
+            );
+        }
+
+        if cfg!(debug_assertions) {
+            for rect in self.rects_exceeding_max_rect() {
+                warn!("Clip rect {:?} exceeds the maximum clip rect, and is clamped", rect);
+            }
+        }
+    }
+
+    // The local rects of the enabled clips that extend past the
+    // `LayerRect::max_rect()` the outer bounds are calculated from. Those
+    // get clamped silently, which tends to hide layout bugs.
+    fn rects_exceeding_max_rect(&self) -> Vec<LayerRect> {
+        let max_rect = LayerRect::max_rect();
+        self.enabled_clips()
+            .map(|&(ref clip, _)| clip.local_rect())
+            .filter(|rect| !max_rect.contains_rect(rect))
+            .collect()
+    }
+
+    fn calculate_inner_and_outer_rects<'a, I>(
+        clips: I,
+        seed_rect: Option<LayerRect>,
+    ) -> (LayerRect, BoundsKind)
+    where
+        I: Iterator<Item = &'a ClipSource>,
+    {
+        let mut clips = clips.peekable();
+        if clips.peek().is_none() {
+            return match seed_rect {
+                Some(rect) => (rect, BoundsKind::from_rect(Some(rect))),
+                None => (LayerRect::zero(), BoundsKind::Unknown),
+            };
         }
 
         // Depending on the complexity of the clip, we may either know the outer and/or inner
         // rect, or neither or these.  In the case of a clip-out, we currently set the mask bounds
         // to be unknown. This is conservative, but ensures correctness. In the future we can make
         // this a lot more clever with some proper region handling.
-        let mut local_outer = Some(LayerRect::max_rect());
+        let mut local_outer = Some(seed_rect.unwrap_or_else(LayerRect::max_rect));
         let mut local_inner = local_outer;
         let mut can_calculate_inner_rect = true;
         let mut can_calculate_outer_rect = true;
@@ -173,6 +679,13 @@ impl ClipSources {
                     local_outer = local_outer.and_then(|r| r.intersection(&rect));
                     local_inner = local_inner.and_then(|r| r.intersection(&rect));
                 }
+                ClipSource::RectWithHoles { ref rect, ref holes } => {
+                    local_outer = local_outer.and_then(|r| r.intersection(rect));
+                    local_inner = match holes.is_empty() {
+                        true => local_inner.and_then(|r| r.intersection(rect)),
+                        false => None,
+                    };
+                }
                 ClipSource::RoundedRectangle(ref rect, ref radius, mode) => {
                     // Once we encounter a clip-out, we just assume the worst
                     // case clip mask size, for now.
@@ -184,21 +697,32 @@ impl ClipSources {
 
                     local_outer = local_outer.and_then(|r| r.intersection(rect));
 
-                    let inner_rect = extract_inner_rect_safe(rect, radius);
-                    local_inner = local_inner
-                        .and_then(|r| inner_rect.and_then(|ref inner| r.intersection(inner)));
+                    // Pixel snapping may still leave no room for the inner rect
+                    // of a fully rounded rect, in which case it's empty.
+                    let center = LayerPoint::new(
+                        rect.origin.x + 0.5 * rect.size.width,
+                        rect.origin.y + 0.5 * rect.size.height,
+                    );
+                    let inner_rect = extract_inner_rect_safe(rect, &clamp_radii(rect, radius))
+                        .unwrap_or_else(|| LayerRect::new(center, LayerSize::zero()));
+                    local_inner = local_inner.and_then(|r| r.intersection(&inner_rect));
                 }
-                ClipSource::BorderCorner { .. } => {
-                    can_calculate_inner_rect = false;
-                    can_calculate_outer_rect = false;
-                    break;
+                ClipSource::BorderCorner(ref source) => {
+                    // Border corner clips are only applied to the corner segment
+                    // of a border, so nothing is drawn outside of the corner. Any
+                    // part of the corner may be masked out, though.
+                    let corner_rect = source.corner_rect();
+                    local_outer = local_outer.and_then(|r| r.intersection(&corner_rect));
+                    local_inner = None;
                 }
             }
         }
 
+        // If the clips don't overlap the outer rect is known, but empty.
         let outer = match can_calculate_outer_rect {
-            true => local_outer,
-            false => None,
+            true => BoundsKind::from_rect(local_outer),
+            false if seed_rect.is_some() => BoundsKind::from_rect(seed_rect),
+            false => BoundsKind::Unknown,
         };
 
         let inner = match can_calculate_inner_rect {
@@ -209,12 +733,177 @@ impl ClipSources {
         (inner, outer)
     }
 
+    /// Upload the clips to the GPU cache and request their image masks.
+    /// Returns the keys of the requested image masks, which is empty if
+    /// this clip has already been updated this frame.
+    ///
+    /// If the mask would cover more than `max_mask_texels` device pixels,
+    /// nothing is uploaded and `over_budget` is set, in which case the
+    /// caller has to avoid drawing the mask.
     pub fn update(
         &mut self,
         gpu_cache: &mut GpuCache,
         resource_cache: &mut ResourceCache,
+        transform: &LayerToWorldTransform,
+        device_pixel_ratio: f32,
+        max_mask_texels: Option<u64>,
+    ) -> Vec<ImageKey> {
+        self.prepare_update(transform, device_pixel_ratio, max_mask_texels);
+        self.update_with(gpu_cache, |images, gpu_cache| {
+            for image in images {
+                resource_cache.request_image(*image, ImageRendering::Auto, None, gpu_cache);
+            }
+        })
+    }
+
+    fn update_with<F>(
+        &mut self,
+        gpu_cache: &mut GpuCache,
+        request_images: F,
+    ) -> Vec<ImageKey>
+    where
+        F: FnOnce(&[ImageKey], &mut GpuCache),
+    {
+        if !self.begin_update(gpu_cache.frame_id()) {
+            return Vec::new();
+        }
+
+        // Nothing gets drawn through an empty rectangle, and the empty
+        // bounds cull whatever the clip applies to, so the clips are
+        // neither uploaded nor are their masks requested. The same goes
+        // for masks over budget, which the caller won't draw.
+        let images = if self.skips_upload() {
+            Vec::new()
+        } else {
+            self.update_gpu_cache(gpu_cache);
+            let images = self.mask_images();
+            request_images(&images, gpu_cache);
+            images
+        };
+        self.mark_images_requested();
+        images
+    }
+
+    // The keys of the enabled image masks, in clip order.
+    fn mask_images(&self) -> Vec<ImageKey> {
+        self.enabled_clips()
+            .filter_map(|&(ref source, _)| match *source {
+                ClipSource::Image(ref mask) => Some(mask.image),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Set up the parts of the update that depend on the transform.
+    fn prepare_update(
+        &mut self,
+        transform: &LayerToWorldTransform,
+        device_pixel_ratio: f32,
+        max_mask_texels: Option<u64>,
     ) {
-        for &mut (ref mut source, ref mut handle) in &mut self.clips {
+        // The bounds are cached, so `get_screen_bounds` doesn't compute
+        // them again for the same transform.
+        self.over_budget = match max_mask_texels {
+            Some(max) if self.is_masking => {
+                let (_, outer_rect) = self.cached_bounds(transform, device_pixel_ratio);
+                outer_rect.map_or(false, |rect| rect_texels(&rect) > max)
+            }
+            _ => false,
+        };
+        if self.over_budget && !self.reported_over_budget {
+            warn!("Skipping a clip mask over the texel budget");
+            self.reported_over_budget = true;
+        }
+    }
+
+    /// Whether the mask was too large for the budget passed to the last
+    /// update, so that the clips weren't uploaded.
+    pub fn over_budget(&self) -> bool {
+        self.over_budget
+    }
+
+    fn skips_upload(&self) -> bool {
+        self.over_budget || self.has_empty_rectangle()
+    }
+
+    fn mark_images_requested(&mut self) {
+        let skipped = self.skips_upload();
+        let clips = self.clips.iter().zip(self.active.iter());
+        for (requested, (&(ref source, _), &enabled)) in self.requested.iter_mut().zip(clips) {
+            *requested = enabled && !skipped && match *source {
+                ClipSource::Image(..) => true,
+                _ => false,
+            };
+        }
+    }
+
+    /// Whether the last update requested the masks of all the enabled
+    /// image clips, which have to be ready before the chain is drawn.
+    pub fn all_images_requested(&self) -> bool {
+        self.clips
+            .iter()
+            .zip(self.active.iter().zip(self.requested.iter()))
+            .all(|(&(ref source, _), (&enabled, &requested))| match *source {
+                ClipSource::Image(..) => !enabled || requested,
+                _ => true,
+            })
+    }
+
+    fn has_empty_rectangle(&self) -> bool {
+        self.enabled_clips().any(|&(ref clip, _)| match *clip {
+            ClipSource::Rectangle(ref rect) => rect.size.width <= 0.0 || rect.size.height <= 0.0,
+            _ => false,
+        })
+    }
+
+    // Clips shared between several nodes or primitives are updated more than
+    // once per frame. Only the first update of a frame needs to do anything.
+    fn begin_update(&mut self, frame_id: FrameId) -> bool {
+        // Without clips there is nothing to upload.
+        if !self.has_clips() || self.last_update_frame == Some(frame_id) {
+            return false;
+        }
+        self.last_update_frame = Some(frame_id);
+        true
+    }
+
+    /// For chains of several rectangle clips, the intersection of
+    /// the rectangles. Such chains are uploaded and rendered as this single
+    /// rectangle, using the GPU cache entry of the first enabled clip.
+    pub fn combined_rect(&self) -> Option<LayerRect> {
+        let mut clip_count = 0;
+        let mut combined = LayerRect::max_rect();
+        for &(ref clip, _) in self.enabled_clips() {
+            match *clip {
+                ClipSource::Rectangle(ref rect) => {
+                    combined = combined.intersection(rect).unwrap_or(LayerRect::zero());
+                    clip_count += 1;
+                }
+                _ => return None,
+            }
+        }
+        if clip_count > 1 {
+            Some(combined)
+        } else {
+            None
+        }
+    }
+
+    fn update_gpu_cache(&mut self, gpu_cache: &mut GpuCache) {
+        if let Some(combined_rect) = self.combined_rect() {
+            let index = self.active.iter().position(|&active| active).unwrap();
+            if let Some(mut request) = gpu_cache.request(&mut self.clips[index].1) {
+                ClipData::uniform(combined_rect, 0.0, ClipMode::Clip).write(&mut request);
+            }
+            return;
+        }
+
+        for (&mut (ref mut source, ref mut handle), &active) in
+            self.clips.iter_mut().zip(self.active.iter())
+        {
+            if !active {
+                continue;
+            }
             if let Some(mut request) = gpu_cache.request(handle) {
                 match *source {
                     ClipSource::Image(ref mask) => {
@@ -229,35 +918,223 @@ impl ClipSources {
                         let data = ClipData::rounded_rect(rect, radius, mode);
                         data.write(&mut request);
                     }
+                    ClipSource::RectWithHoles { ref rect, ref holes } => {
+                        if holes.len() > MAX_CLIP_HOLES {
+                            warn!("Ignoring {} clip holes past the first {}.",
+                                  holes.len() - MAX_CLIP_HOLES, MAX_CLIP_HOLES);
+                        }
+                        // The rect, followed by a clip-out rect for each hole.
+                        ClipData::uniform(*rect, 0.0, ClipMode::Clip).write(&mut request);
+                        for hole in holes.iter().take(MAX_CLIP_HOLES) {
+                            ClipData::uniform(*hole, 0.0, ClipMode::ClipOut).write(&mut request);
+                        }
+                    }
                     ClipSource::BorderCorner(ref mut source) => {
                         source.write(request);
                     }
                 }
             }
-
-            if let ClipSource::Image(ref mask) = *source {
-                resource_cache.request_image(mask.image, ImageRendering::Auto, None, gpu_cache);
-            }
         }
     }
 
+    /// A compact, human readable summary of the clip chain, for use
+    /// in debug overlays, e.g. `rect(0,0,100,100) ∩ rounded(r=8)`.
+    pub fn debug_string(&self) -> String {
+        let parts: Vec<String> = self.clips
+            .iter()
+            .map(|&(ref source, _)| match *source {
+                ClipSource::Rectangle(ref rect) => format!(
+                    "rect({},{},{},{})",
+                    rect.origin.x,
+                    rect.origin.y,
+                    rect.size.width,
+                    rect.size.height,
+                ),
+                ClipSource::RoundedRectangle(_, ref radii, mode) => {
+                    let r = radii.top_left.width;
+                    let radius = if *radii == BorderRadius::uniform(r) {
+                        format!("r={}", r)
+                    } else {
+                        "r=mixed".to_owned()
+                    };
+                    let mode = match mode {
+                        ClipMode::ClipOut => ", out",
+                        ClipMode::Clip => "",
+                    };
+                    format!("rounded({}{})", radius, mode)
+                }
+                ClipSource::Image(ref mask) => format!("image(key={})", mask.image.1),
+                ClipSource::RectWithHoles { ref holes, .. } => {
+                    format!("rect-with-holes({})", holes.len())
+                }
+                ClipSource::BorderCorner(..) => "border".to_owned(),
+            })
+            .collect();
+
+        parts.join(" \u{2229} ")
+    }
+
+    /// Whether text can keep its subpixel AA under this clip, applied with
+    /// `transform`. That's only the case for rectangle clips that
+    /// stay axis-aligned, which are applied without a mask, since a mask
+    /// would be applied to all the color channels alike.
+    pub fn allows_subpixel_aa(&self, transform: &LayerToWorldTransform) -> bool {
+        !self.is_masking && transform.preserves_2d_axis_alignment()
+    }
+
     /// Whether or not this ClipSources has any clips (does any clipping).
     pub fn has_clips(&self) -> bool {
-        !self.clips.is_empty()
+        self.enabled_clips().next().is_some()
     }
 
-    pub fn get_screen_bounds(
+    /// The device space inner and outer bounds of this clip. Unlike `update`
+    /// this doesn't need any GPU resources, so it can be used during scene
+    /// building, before the GPU cache is available.
+    pub fn compute_bounds(
+        &self,
+        transform: &LayerToWorldTransform,
+        device_pixel_ratio: f32,
+    ) -> (DeviceIntRect, Option<DeviceIntRect>) {
+        self.compute_snapped_bounds(transform, device_pixel_ratio, SnapMode::Expand)
+    }
+
+    /// Like `compute_bounds`, with control over how fractional device
+    /// coordinates are snapped to pixels.
+    pub fn compute_snapped_bounds(
         &self,
         transform: &LayerToWorldTransform,
         device_pixel_ratio: f32,
+        snap_mode: SnapMode,
     ) -> (DeviceIntRect, Option<DeviceIntRect>) {
-        let screen_inner_rect =
-            calculate_screen_bounding_rect(transform, &self.local_inner_rect, device_pixel_ratio);
-        let screen_outer_rect = self.local_outer_rect.map(|outer_rect|
-            calculate_screen_bounding_rect(transform, &outer_rect, device_pixel_ratio)
+        let outer_rect = match self.local_outer_rect {
+            BoundsKind::Known(outer_rect) => outer_rect,
+            BoundsKind::Empty => return (DeviceIntRect::zero(), Some(DeviceIntRect::zero())),
+            BoundsKind::Unknown => {
+                let screen_inner_rect = calculate_screen_bounding_rect_snapped(
+                    transform,
+                    &self.local_inner_rect,
+                    device_pixel_ratio,
+                    snap_mode,
+                );
+                return (screen_inner_rect, None);
+            }
+        };
+
+        let rect = TransformedRect::new(
+            transform,
+            &outer_rect,
+            &self.local_inner_rect,
+            device_pixel_ratio,
+            snap_mode,
         );
+        (rect.inner_rect, Some(rect.bounding_rect))
+    }
+
+    // Like `compute_bounds`, reusing the bounds computed for the same
+    // transform recently.
+    fn cached_bounds(
+        &mut self,
+        transform: &LayerToWorldTransform,
+        device_pixel_ratio: f32,
+    ) -> (DeviceIntRect, Option<DeviceIntRect>) {
+        let key = DeviceBoundsKey::new(transform, device_pixel_ratio);
+        if let Some(bounds) = self.device_bounds_cache.get(&key) {
+            return bounds;
+        }
+
+        let bounds = self.compute_bounds(transform, device_pixel_ratio);
+        if let (ref inner_rect, Some(ref outer_rect)) = bounds {
+            debug_assert!(
+                rect_is_empty(inner_rect) || outer_rect.contains_rect(inner_rect),
+                "Device inner rect {:?} is not inside the outer rect {:?}",
+                inner_rect,
+                outer_rect
+            );
+        }
+        self.device_bounds_cache.insert(key, bounds);
+        bounds
+    }
+
+    /// The device space inner and outer bounds of this clip. Like the local
+    /// bounds, the inner rect is always either empty or inside the outer
+    /// rect, when that is known.
+    pub fn get_screen_bounds(
+        &mut self,
+        transform: &LayerToWorldTransform,
+        device_pixel_ratio: f32,
+    ) -> (DeviceIntRect, Option<DeviceIntRect>) {
+        self.cached_bounds(transform, device_pixel_ratio)
+    }
+
+    /// Whether a primitive covering `prim_device_rect` is entirely clipped
+    /// out under `transform`. This is conservative, so clips with unknown
+    /// outer bounds never cull anything.
+    pub fn culls(
+        &mut self,
+        transform: &LayerToWorldTransform,
+        device_pixel_ratio: f32,
+        prim_device_rect: &DeviceIntRect,
+    ) -> bool {
+        match self.cached_bounds(transform, device_pixel_ratio).1 {
+            Some(ref outer_rect) => !outer_rect.intersects(prim_device_rect),
+            None => false,
+        }
+    }
+}
+
+// Rects aren't hashable, so key single rectangle clips on their raw bits.
+// Adding zero turns -0.0 into 0.0, so that equal rects get equal keys.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct RectangleClipKey([u32; 4]);
 
-        (screen_inner_rect, screen_outer_rect)
+impl RectangleClipKey {
+    fn new(rect: &LayerRect) -> RectangleClipKey {
+        RectangleClipKey([
+            (rect.origin.x + 0.0).to_bits(),
+            (rect.origin.y + 0.0).to_bits(),
+            (rect.size.width + 0.0).to_bits(),
+            (rect.size.height + 0.0).to_bits(),
+        ])
+    }
+}
+
+// The least number of entries the interner keeps before pruning the ones
+// whose clip sources are gone.
+const MIN_INTERNER_PRUNE_THRESHOLD: usize = 64;
+
+/// An index of the clip sources in a clip store that consist of a single
+/// rectangle. Identical rectangle clips (such as the viewport clip, which
+/// shows up in many clip chains) share one entry in the clip store, and
+/// therefore also share their GPU cache data.
+pub struct RectangleClipInterner {
+    entries: FastHashMap<RectangleClipKey, ClipSourcesWeakHandle>,
+    // The number of entries at which the dead ones are pruned. It's twice
+    // the number left by the last pruning, so pruning stays amortized O(1).
+    prune_threshold: usize,
+}
+
+impl RectangleClipInterner {
+    pub fn new() -> RectangleClipInterner {
+        RectangleClipInterner {
+            entries: FastHashMap::default(),
+            prune_threshold: MIN_INTERNER_PRUNE_THRESHOLD,
+        }
+    }
+
+    pub fn insert(&mut self, clip_store: &mut ClipStore, rect: LayerRect) -> ClipSourcesHandle {
+        let key = RectangleClipKey::new(&rect);
+        if let Some(handle) = self.entries.get(&key).and_then(|weak| clip_store.upgrade(weak)) {
+            return handle;
+        }
+
+        if self.entries.len() >= self.prune_threshold {
+            self.entries.retain(|_, weak| clip_store.get_opt(weak).is_some());
+            self.prune_threshold = MIN_INTERNER_PRUNE_THRESHOLD.max(2 * self.entries.len());
+        }
+
+        let handle = clip_store.insert(ClipSources::new_rectangle(rect));
+        self.entries.insert(key, handle.weak());
+        handle
     }
 }
 
@@ -336,3 +1213,272 @@ fn rounded_rectangle_contains_point(point: &LayoutPoint,
 
     true
 }
+
+fn rect_texels(rect: &DeviceIntRect) -> u64 {
+    rect.size.width.max(0) as u64 * rect.size.height.max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::{DeviceIntPoint, DeviceIntSize, IdNamespace};
+    use border::{BorderCorner, BorderCornerClipKind, BorderCornerKind};
+    use euclid::Radians;
+    use std::{f32, ptr};
+
+    fn rect(x: f32, y: f32, w: f32, h: f32) -> LayerRect {
+        LayerRect::new(LayerPoint::new(x, y), LayerSize::new(w, h))
+    }
+
+    fn device_rect(x: i32, y: i32, w: i32, h: i32) -> DeviceIntRect {
+        DeviceIntRect::new(DeviceIntPoint::new(x, y), DeviceIntSize::new(w, h))
+    }
+
+    fn rounded(rect: LayerRect, radius: f32, mode: ClipMode) -> ClipSource {
+        ClipSource::RoundedRectangle(rect, BorderRadius::uniform(radius), mode)
+    }
+
+    fn image_mask(key: u32, rect: LayerRect) -> ImageMask {
+        ImageMask {
+            image: ImageKey::new(IdNamespace(0), key),
+            rect,
+            repeat: false,
+        }
+    }
+
+    fn kinds(clips: &ClipSources) -> Vec<ClipKind> {
+        clips.clips().iter().map(|&(ref clip, _)| clip.kind()).collect()
+    }
+
+    fn region(main: LayerRect, complex: Vec<ComplexClipRegion>, mask: Option<ImageMask>) -> ClipSources {
+        ClipSources::from(ClipRegion::create_for_clip_node(main, complex, mask, &LayoutVector2D::zero()))
+    }
+
+    #[test]
+    fn clip_regions_to_clip_sources() {
+        let main = rect(0.0, 0.0, 100.0, 100.0);
+        let hole = rect(40.0, 40.0, 20.0, 20.0);
+        let clip_out = |radius| {
+            ComplexClipRegion::new(hole, BorderRadius::uniform(radius), ClipMode::ClipOut)
+        };
+
+        let clips = region(main, vec![], None);
+        assert_eq!(clips.local_outer_rect, BoundsKind::Known(main));
+        assert_eq!(clips.local_inner_rect, main);
+
+        // Rectangular clip-outs become holes, rounded ones can't.
+        let clips = region(main, vec![clip_out(0.0)], None);
+        assert_eq!(kinds(&clips), vec![ClipKind::RectWithHoles]);
+        assert_eq!(clips.local_outer_rect, BoundsKind::Known(main));
+        assert!(!clips.clips()[0].0.contains(&LayerPoint::new(50.0, 50.0)));
+        let clips = region(main, vec![clip_out(5.0)], None);
+        assert_eq!(clips.clips().len(), 2);
+        assert_eq!(clips.local_outer_rect, BoundsKind::Unknown);
+
+        let clips = region(main, vec![], Some(image_mask(1, main)));
+        assert_eq!(kinds(&clips), vec![ClipKind::Rectangle, ClipKind::Image]);
+
+        let negative = rect(10.0, 10.0, -50.0, 20.0);
+        let complex = ComplexClipRegion::new(negative, BorderRadius::uniform(5.0), ClipMode::Clip);
+        let offset_region = ClipRegion::create_for_clip_node(
+            negative,
+            vec![complex],
+            None,
+            &LayoutVector2D::new(5.0, 5.0),
+        );
+        assert_eq!(offset_region.main, rect(15.0, 15.0, 0.0, 20.0));
+        assert_eq!(ClipSources::from(offset_region).local_outer_rect, BoundsKind::Empty);
+    }
+
+    #[test]
+    fn local_bounds_of_clip_chains() {
+        let left = rect(0.0, 0.0, 10.0, 10.0);
+        let right = rect(50.0, 0.0, 10.0, 10.0);
+        let disjoint = ClipSources::new(vec![ClipSource::Rectangle(left), ClipSource::Rectangle(right)]);
+        assert_eq!(disjoint.local_outer_rect, BoundsKind::Empty);
+        let clip_out = ClipSources::new(vec![
+            ClipSource::Rectangle(left),
+            rounded(right, 0.0, ClipMode::ClipOut),
+        ]);
+        assert_eq!(clip_out.local_outer_rect, BoundsKind::Unknown);
+
+        // A NaN rect is replaced by an empty one, which clips out everything.
+        let nan_rect = rect(f32::NAN, 0.0, 50.0, 50.0);
+        let clips = ClipSources::new(vec![ClipSource::Rectangle(left), ClipSource::Rectangle(nan_rect)]);
+        assert_eq!(clips.local_outer_rect, BoundsKind::Empty);
+        assert!(clips.local_inner_rect.origin.x.is_finite());
+
+        let corner = match BorderCornerKind::new_mask(
+            BorderCornerClipKind::Dash,
+            4.0,
+            4.0,
+            BorderCorner::TopLeft,
+            LayerSize::new(10.0, 10.0),
+            rect(0.0, 0.0, 100.0, 50.0),
+        ) {
+            BorderCornerKind::Mask(data, radius, widths, kind) => {
+                BorderCornerClipSource::new(data, radius, widths, kind)
+            }
+            _ => unreachable!(),
+        };
+        let clips = ClipSources::new(vec![
+            ClipSource::Rectangle(rect(5.0, 5.0, 100.0, 50.0)),
+            ClipSource::BorderCorner(corner),
+        ]);
+        assert_eq!(clips.local_outer_rect, BoundsKind::Known(rect(5.0, 5.0, 5.0, 5.0)));
+        assert_eq!(clips.local_inner_rect, LayerRect::zero());
+
+        let outer = rect(10.5, 20.25, 100.0, 60.0);
+        let clips = ClipSources::new(vec![rounded(outer, 12.0, ClipMode::Clip)]);
+        assert_eq!(clips.local_outer_rect, BoundsKind::Known(outer));
+        assert!(outer.contains_rect(&clips.local_inner_rect));
+    }
+
+    #[test]
+    fn seeded_bounds_are_tighter() {
+        let prim_rect = rect(10.0, 10.0, 50.0, 50.0);
+        let clip_rect = rect(0.0, 0.0, 40.0, 100.0);
+        let mut clips = ClipSources::new(vec![rounded(clip_rect, 4.0, ClipMode::Clip)]);
+        clips.update_bounds_with_seed(Some(prim_rect));
+        assert_eq!(clips.local_outer_rect, BoundsKind::Known(rect(10.0, 10.0, 30.0, 50.0)));
+
+        // The seed also bounds clips that are otherwise unbounded.
+        let mut clip_out = ClipSources::new(vec![rounded(clip_rect, 4.0, ClipMode::ClipOut)]);
+        clip_out.update_bounds_with_seed(Some(prim_rect));
+        assert_eq!(clip_out.local_outer_rect, BoundsKind::Known(prim_rect));
+        clip_out.update_bounds_with_seed(None);
+        assert_eq!(clip_out.local_outer_rect, BoundsKind::Unknown);
+    }
+
+    #[test]
+    fn nested_rounded_clips() {
+        let outer = rect(0.0, 0.0, 100.0, 100.0);
+        let inner = outer.inflate(-10.0, -10.0);
+        let enabled_rects = |clips: Vec<ClipSource>| -> Vec<LayerRect> {
+            ClipSources::new(clips).enabled_clips().map(|&(ref clip, _)| clip.local_rect()).collect()
+        };
+
+        // Inset by the difference of the radii, the corners are concentric.
+        assert_eq!(
+            enabled_rects(vec![rounded(outer, 20.0, ClipMode::Clip), rounded(inner, 10.0, ClipMode::Clip)]),
+            vec![inner]
+        );
+        // The sharp corners of the inner rect poke out of the rounded ones.
+        assert_eq!(
+            enabled_rects(vec![rounded(outer, 20.0, ClipMode::Clip), rounded(inner, 2.0, ClipMode::Clip)]),
+            vec![outer, inner]
+        );
+        // Only one of two equal clips is kept.
+        assert_eq!(
+            enabled_rects(vec![rounded(outer, 20.0, ClipMode::Clip), rounded(outer, 20.0, ClipMode::Clip)]),
+            vec![outer]
+        );
+    }
+
+    #[test]
+    fn device_bounds_of_clip_chains() {
+        let clip_rect = rect(10.0, 20.0, 100.0, 50.0);
+        let mut clips = ClipSources::new(vec![
+            ClipSource::Rectangle(clip_rect),
+            rounded(clip_rect, 10.0, ClipMode::Clip),
+        ]);
+        let transform = LayerToWorldTransform::create_translation(5.0, 5.0, 0.0);
+        let bounds = clips.compute_bounds(&transform, 2.0);
+        assert_eq!(bounds, clips.get_screen_bounds(&transform, 2.0));
+        assert_eq!(bounds.1, Some(device_rect(30, 50, 200, 100)));
+
+        let rotation = LayerToWorldTransform::create_rotation(0.0, 0.0, 1.0, Radians::new(0.3));
+        let (inner, outer) = clips.get_screen_bounds(&rotation, 1.5);
+        assert!(outer.unwrap().contains_rect(&inner));
+
+        let identity = LayerToWorldTransform::identity();
+        let fractional = ClipSources::new_rectangle(rect(0.25, 0.75, 10.5, 10.0));
+        for &(snap_mode, expected) in &[
+            (SnapMode::Truncate, device_rect(0, 0, 10, 10)),
+            (SnapMode::Round, device_rect(0, 1, 11, 10)),
+            (SnapMode::Expand, device_rect(0, 0, 11, 11)),
+        ] {
+            let bounds = fractional.compute_snapped_bounds(&identity, 1.0, snap_mode);
+            assert_eq!(bounds, (expected, Some(expected)));
+        }
+        assert_eq!(fractional.compute_bounds(&identity, 1.0).1, Some(device_rect(0, 0, 11, 11)));
+
+        let empty = ClipSources::new(vec![
+            ClipSource::Rectangle(rect(0.0, 0.0, 10.0, 10.0)),
+            ClipSource::Rectangle(rect(50.0, 0.0, 10.0, 10.0)),
+        ]);
+        let zero = DeviceIntRect::zero();
+        assert_eq!(empty.compute_bounds(&identity, 1.0), (zero, Some(zero)));
+    }
+
+    #[test]
+    fn culling_against_device_rect() {
+        let clip_rect = rect(0.0, 0.0, 100.0, 100.0);
+        let transform = LayerToWorldTransform::identity();
+        let prim_rect = |x, y| device_rect(x, y, 10, 10);
+
+        let mut clips = ClipSources::new(vec![ClipSource::Rectangle(clip_rect)]);
+        assert!(!clips.culls(&transform, 1.0, &prim_rect(50, 50)));
+        assert!(clips.culls(&transform, 1.0, &prim_rect(500, 500)));
+        // The primitive is compared against the bounds under its transform.
+        let scale = LayerToWorldTransform::create_scale(10.0, 10.0, 1.0);
+        assert!(!clips.culls(&scale, 1.0, &prim_rect(500, 500)));
+
+        let mut unknown = ClipSources::new(vec![rounded(clip_rect, 8.0, ClipMode::ClipOut)]);
+        assert!(!unknown.culls(&transform, 1.0, &prim_rect(500, 500)));
+    }
+
+    #[test]
+    fn clip_chain_properties() {
+        let clip_rect = rect(0.0, 0.0, 100.0, 100.0);
+        let small_rect = rect(10.0, 20.0, 50.0, 50.0);
+        let identity = LayerToWorldTransform::identity();
+
+        let rects = ClipSources::new(vec![
+            ClipSource::Rectangle(clip_rect),
+            ClipSource::Rectangle(small_rect),
+        ]);
+        assert_eq!(rects.combined_rect(), Some(small_rect));
+        assert!(rects.allows_subpixel_aa(&identity));
+        // Rotated rectangles need a mask.
+        let rotation = LayerToWorldTransform::create_rotation(0.0, 0.0, 1.0, Radians::new(0.3));
+        assert!(!rects.allows_subpixel_aa(&rotation));
+
+        let mut clips = ClipSources::new(vec![
+            ClipSource::Image(image_mask(3, clip_rect)),
+            rounded(small_rect, 8.0, ClipMode::Clip),
+            ClipSource::Rectangle(clip_rect),
+        ]);
+        assert!(!clips.allows_subpixel_aa(&identity));
+        assert_eq!(clips.combined_rect(), None);
+
+        let outer = clips.local_outer_rect;
+        clips.reorder_for_gpu();
+        assert_eq!(kinds(&clips), vec![ClipKind::Rectangle, ClipKind::RoundedRectangle, ClipKind::Image]);
+        assert_eq!(clips.local_outer_rect, outer);
+        assert_eq!(
+            clips.debug_string(),
+            "rect(0,0,100,100) \u{2229} rounded(r=8) \u{2229} image(key=3)"
+        );
+    }
+
+    #[test]
+    fn identical_rectangle_clips_are_interned() {
+        let mut clip_store = ClipStore::new();
+        let mut interner = RectangleClipInterner::new();
+        let viewport = rect(0.0, 0.0, 800.0, 600.0);
+
+        let first = interner.insert(&mut clip_store, viewport);
+        let negative_zero = interner.insert(&mut clip_store, rect(-0.0, -0.0, 800.0, 600.0));
+        let other = interner.insert(&mut clip_store, viewport.inflate(-1.0, -1.0));
+        assert!(ptr::eq(clip_store.get(&first), clip_store.get(&negative_zero)));
+        assert!(!ptr::eq(clip_store.get(&first), clip_store.get(&other)));
+
+        // Entries of freed clips don't pile up.
+        for i in 0 .. 1000 {
+            let handle = interner.insert(&mut clip_store, rect(i as f32, 0.0, 10.0, 10.0));
+            clip_store.free(handle);
+        }
+        assert!(interner.entries.len() <= MIN_INTERNER_PRUNE_THRESHOLD);
+    }
+}
@@ -200,7 +200,7 @@ impl ClipScrollTree {
             return false;
         }
 
-        for &(ref clip, _) in clip_store.get(&clip_sources_handle).clips() {
+        for &(ref clip, _) in clip_store.get(&clip_sources_handle).enabled_clips() {
             if !clip.contains(&transformed_point) {
                 cache.insert(*node_id, None);
                 return false;
@@ -530,7 +530,9 @@ impl ClipScrollTree {
                 pt.new_level("Clip".to_owned());
 
                 pt.add_item(format!("id: {:?}", id));
-                let clips = clip_store.get(&clip_sources_handle).clips();
+                let clip_sources = clip_store.get(&clip_sources_handle);
+                pt.add_item(format!("summary: {}", clip_sources.debug_string()));
+                let clips = clip_sources.clips();
                 pt.new_level(format!("Clip Sources [{}]", clips.len()));
                 for source in clips {
                     pt.add_item(format!("{:?}", source));
@@ -14,7 +14,7 @@ use api::{ScrollSensitivity, Shadow, TileOffset, TransformStyle};
 use api::{PremultipliedColorF, WorldPoint, YuvColorSpace, YuvData};
 use app_units::Au;
 use border::ImageBorderSegment;
-use clip::{ClipRegion, ClipSource, ClipSources, ClipStore, Contains};
+use clip::{ClipRegion, ClipSource, ClipSources, ClipStore, Contains, RectangleClipInterner};
 use clip_scroll_node::{ClipScrollNode, NodeType};
 use clip_scroll_tree::ClipScrollTree;
 use euclid::{SideOffsets2D, vec2};
@@ -73,6 +73,7 @@ pub struct FrameBuilderConfig {
     pub enable_scrollbars: bool,
     pub default_font_render_mode: FontRenderMode,
     pub debug: bool,
+    pub max_clip_mask_texels: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -100,6 +101,7 @@ pub struct FrameBuilder {
     background_color: Option<ColorF>,
     prim_store: PrimitiveStore,
     pub clip_store: ClipStore,
+    rectangle_clips: RectangleClipInterner,
     hit_testing_runs: Vec<HitTestingRun>,
     pub config: FrameBuilderConfig,
 
@@ -126,6 +128,7 @@ pub struct FrameBuilder {
 
 pub struct PrimitiveContext<'a> {
     pub device_pixel_ratio: f32,
+    pub max_clip_mask_texels: Option<u64>,
     pub display_list: &'a BuiltDisplayList,
     pub clip_node: &'a ClipScrollNode,
     pub scroll_node: &'a ClipScrollNode,
@@ -134,12 +137,14 @@ pub struct PrimitiveContext<'a> {
 impl<'a> PrimitiveContext<'a> {
     pub fn new(
         device_pixel_ratio: f32,
+        max_clip_mask_texels: Option<u64>,
         display_list: &'a BuiltDisplayList,
         clip_node: &'a ClipScrollNode,
         scroll_node: &'a ClipScrollNode,
     ) -> Self {
         PrimitiveContext {
             device_pixel_ratio,
+            max_clip_mask_texels,
             display_list,
             clip_node,
             scroll_node,
@@ -159,12 +164,14 @@ impl FrameBuilder {
             sc_stack: Vec::new(),
             prim_store: PrimitiveStore::new(),
             clip_store: ClipStore::new(),
+            rectangle_clips: RectangleClipInterner::new(),
             screen_rect: DeviceUintRect::zero(),
             background_color: None,
             config: FrameBuilderConfig {
                 enable_scrollbars: false,
                 default_font_render_mode: FontRenderMode::Mono,
                 debug: false,
+                max_clip_mask_texels: None,
             },
         }
     }
@@ -185,6 +192,7 @@ impl FrameBuilder {
             sc_stack: recycle_vec(self.sc_stack),
             prim_store: self.prim_store.recycle(),
             clip_store: self.clip_store.recycle(),
+            rectangle_clips: RectangleClipInterner::new(),
             screen_rect,
             background_color,
             config,
@@ -211,7 +219,11 @@ impl FrameBuilder {
 
         let stacking_context = self.sc_stack.last().expect("bug: no stacking context!");
 
-        let clip_sources = self.clip_store.insert(ClipSources::new(clip_sources));
+        // A primitive never draws outside of its own rect, so its clips can't either.
+        let mut clip_sources = ClipSources::new(clip_sources);
+        clip_sources.reorder_for_gpu();
+        clip_sources.update_bounds_with_seed(Some(info.rect));
+        let clip_sources = self.clip_store.insert(clip_sources);
         let prim_index = self.prim_store.add_primitive(
             &info.rect,
             &info.local_clip.clip_rect(),
@@ -320,7 +332,7 @@ impl FrameBuilder {
             );
 
             // No clip sources needed for the main framebuffer.
-            let clip_sources = self.clip_store.insert(ClipSources::new(Vec::new()));
+            let clip_sources = self.clip_store.insert(ClipSources::empty());
 
             // Add root picture primitive. The provided layer rect
             // is zero, because we don't yet know the size of the
@@ -406,7 +418,7 @@ impl FrameBuilder {
                 current_reference_frame_id,
                 None,
             );
-            let src_clip_sources = self.clip_store.insert(ClipSources::new(Vec::new()));
+            let src_clip_sources = self.clip_store.insert(ClipSources::empty());
 
             let src_prim_index = self.prim_store.add_primitive(
                 &LayerRect::zero(),
@@ -437,7 +449,7 @@ impl FrameBuilder {
                 current_reference_frame_id,
                 None,
             );
-            let src_clip_sources = self.clip_store.insert(ClipSources::new(Vec::new()));
+            let src_clip_sources = self.clip_store.insert(ClipSources::empty());
 
             let src_prim_index = self.prim_store.add_primitive(
                 &LayerRect::zero(),
@@ -491,7 +503,7 @@ impl FrameBuilder {
             frame_output_pipeline_id,
         );
 
-        let sc_clip_sources = self.clip_store.insert(ClipSources::new(Vec::new()));
+        let sc_clip_sources = self.clip_store.insert(ClipSources::empty());
         let sc_prim_index = self.prim_store.add_primitive(
             &LayerRect::zero(),
             &max_clip,
@@ -673,10 +685,13 @@ impl FrameBuilder {
         clip_scroll_tree: &mut ClipScrollTree,
     ) {
         let clip_rect = clip_region.main;
-        let clip_sources = ClipSources::from(clip_region);
-        debug_assert!(clip_sources.has_clips());
-
-        let handle = self.clip_store.insert(clip_sources);
+        let handle = if clip_region.image_mask.is_none() && clip_region.complex_clips.is_empty() {
+            self.rectangle_clips.insert(&mut self.clip_store, clip_rect)
+        } else {
+            let clip_sources = ClipSources::from(clip_region);
+            debug_assert!(clip_sources.has_clips());
+            self.clip_store.insert(clip_sources)
+        };
 
         let node = ClipScrollNode::new_clip_node(pipeline_id, parent_id, handle, clip_rect);
         clip_scroll_tree.add_node(node, new_node_id);
@@ -1325,6 +1340,12 @@ impl FrameBuilder {
                     render_mode = FontRenderMode::Alpha;
                 }
             }
+            // The mask of a rounded local clip can't be applied to each
+            // color channel separately. The clips of the clip chain are
+            // checked each frame, when the clip task is updated.
+            if let LocalClip::RoundedRect(..) = info.local_clip {
+                render_mode = FontRenderMode::Alpha;
+            }
         }
 
         let prim_font = FontInstance::new(
@@ -1345,6 +1366,7 @@ impl FrameBuilder {
             glyph_gpu_blocks: Vec::new(),
             glyph_keys: Vec::new(),
             offset: run_offset,
+            clips_allow_subpixel_aa: true,
         };
 
         // Text shadows that have a blur radius of 0 need to be rendered as normal
@@ -1604,6 +1626,7 @@ impl FrameBuilder {
 
         let root_prim_context = PrimitiveContext::new(
             device_pixel_ratio,
+            self.config.max_clip_mask_texels,
             display_list,
             root_clip_scroll_node,
             root_clip_scroll_node,
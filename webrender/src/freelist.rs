@@ -49,6 +49,7 @@ pub struct WeakFreeListHandle<T> {
 struct Slot<T> {
     next: Option<u32>,
     epoch: Epoch,
+    ref_count: u32,
     value: Option<T>,
 }
 
@@ -128,6 +129,7 @@ impl<T> FreeList<T> {
                 // Remove from free list.
                 self.free_list_head = slot.next;
                 slot.next = None;
+                slot.ref_count = 1;
                 slot.value = Some(item);
 
                 FreeListHandle {
@@ -143,6 +145,7 @@ impl<T> FreeList<T> {
                 self.slots.push(Slot {
                     next: None,
                     epoch,
+                    ref_count: 1,
                     value: Some(item),
                 });
 
@@ -155,11 +158,54 @@ impl<T> FreeList<T> {
         }
     }
 
-    pub fn free(&mut self, id: FreeListHandle<T>) -> T {
+    // Create an additional strong handle from a weak handle, if the
+    // entry it refers to is still alive.
+    pub fn upgrade(&mut self, id: &WeakFreeListHandle<T>) -> Option<FreeListHandle<T>> {
         let slot = &mut self.slots[id.index as usize];
+        if slot.epoch != id.epoch || slot.value.is_none() {
+            return None;
+        }
+        slot.ref_count += 1;
+
+        Some(FreeListHandle {
+            index: id.index,
+            epoch: id.epoch,
+            _marker: PhantomData,
+        })
+    }
+
+    // Release a strong handle. The entry is only freed, and its value
+    // returned, once the last strong handle to it is released, see
+    // upgrade().
+    pub fn free(&mut self, id: FreeListHandle<T>) -> Option<T> {
+        let slot = &mut self.slots[id.index as usize];
+        debug_assert!(slot.ref_count > 0);
+        slot.ref_count -= 1;
+        if slot.ref_count > 0 {
+            return None;
+        }
         slot.next = self.free_list_head;
         slot.epoch = Epoch(slot.epoch.0 + 1);
         self.free_list_head = Some(id.index);
-        slot.value.take().unwrap()
+        slot.value.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_entry_outlives_one_owner() {
+        let mut list = FreeList::new();
+        let first = list.insert(42);
+        let weak = first.weak();
+        let second = list.upgrade(&weak).unwrap();
+
+        assert_eq!(list.free(first), None);
+        assert_eq!(list.get_opt(&weak), Some(&42));
+
+        assert_eq!(list.free(second), Some(42));
+        assert_eq!(list.get_opt(&weak), None);
     }
 }
@@ -121,7 +121,7 @@ impl GpuCacheHandle {
 // A unique address in the GPU cache. These are uploaded
 // as part of the primitive instances, to allow the vertex
 // shader to fetch the specific data.
-#[derive(Copy, Debug, Clone)]
+#[derive(Copy, Debug, Clone, PartialEq)]
 pub struct GpuCacheAddress {
     pub u: u16,
     pub v: u16,
@@ -508,6 +508,11 @@ impl GpuCache {
         }
     }
 
+    /// The id of the current frame.
+    pub fn frame_id(&self) -> FrameId {
+        self.frame_id
+    }
+
     /// Begin a new frame.
     pub fn begin_frame(&mut self) {
         debug_assert!(self.texture.pending_blocks.is_empty());
@@ -730,6 +730,9 @@ pub struct TextRunPrimitiveCpu {
     pub glyph_count: usize,
     pub glyph_keys: Vec<GlyphKey>,
     pub glyph_gpu_blocks: Vec<GpuBlockData>,
+    // Whether the clips applied to the run, as of the last frame it was
+    // prepared in, allow subpixel AA.
+    pub clips_allow_subpixel_aa: bool,
 }
 
 
@@ -742,6 +745,9 @@ impl TextRunPrimitiveCpu {
     ) -> FontInstance {
         let mut font = self.font.clone();
         font.size = font.size.scale_by(device_pixel_ratio);
+        if !self.clips_allow_subpixel_aa {
+            font.render_mode = font.render_mode.limit_by(FontRenderMode::Alpha);
+        }
         if font.render_mode != FontRenderMode::Bitmap &&
            rasterization_kind == RasterizationSpace::Screen {
             if transform.has_perspective_component() || !transform.has_2d_inverse() {
@@ -753,6 +759,18 @@ impl TextRunPrimitiveCpu {
         font
     }
 
+    // Clip masks apply to all the color channels alike, so subpixel AA
+    // can only be used if none of the clips need a mask. The glyphs are
+    // requested with the render mode, so they are looked up again when
+    // that changes.
+    fn set_clips_allow_subpixel_aa(&mut self, allow: bool) {
+        if self.clips_allow_subpixel_aa != allow {
+            self.clips_allow_subpixel_aa = allow;
+            self.glyph_keys.clear();
+            self.glyph_gpu_blocks.clear();
+        }
+    }
+
     fn prepare_for_render(
         &mut self,
         resource_cache: &mut ResourceCache,
@@ -878,6 +896,9 @@ impl ToGpuBlocks for ImageMaskData {
     }
 }
 
+/// The number of GPU blocks written by `ClipData::write`.
+pub const CLIP_DATA_GPU_BLOCKS: usize = 10;
+
 #[derive(Debug)]
 pub struct ClipData {
     rect: ClipRect,
@@ -1402,26 +1423,45 @@ impl PrimitiveStore {
         let extra_clip =  {
             let prim_clips = clip_store.get_mut(&metadata.clip_sources);
             if prim_clips.has_clips() {
-                prim_clips.update(gpu_cache, resource_cache);
+                prim_clips.update(
+                    gpu_cache,
+                    resource_cache,
+                    transform,
+                    prim_context.device_pixel_ratio,
+                    prim_context.max_clip_mask_texels,
+                );
                 let (screen_inner_rect, screen_outer_rect) =
                     prim_clips.get_screen_bounds(transform, prim_context.device_pixel_ratio);
 
+                // The primitive is entirely outside of its own clips.
+                if prim_clips.culls(transform, prim_context.device_pixel_ratio, &prim_screen_rect) {
+                    metadata.screen_rect = None;
+                    return false;
+                }
+
                 if let Some(outer) = screen_outer_rect {
-                    combined_outer_rect = combined_outer_rect.and_then(|r| r.intersection(&outer));
+                    combined_outer_rect =
+                        combined_outer_rect.and_then(|r| r.intersection(&outer));
                 }
 
-                Some(Rc::new(ClipChainNode {
-                    work_item: ClipWorkItem {
-                        scroll_node_data_index: prim_context.scroll_node.node_data_index,
-                        clip_sources: metadata.clip_sources.weak(),
-                        coordinate_system_id: prim_coordinate_system_id,
-                    },
-                    screen_inner_rect,
-                    combined_outer_screen_rect:
-                        combined_outer_rect.unwrap_or_else(DeviceIntRect::zero),
-                    combined_inner_screen_rect: DeviceIntRect::zero(),
-                    prev: None,
-                }))
+                if prim_clips.over_budget() {
+                    // The mask was too large to upload, so the primitive is
+                    // only clipped to the outer rect of its clips.
+                    None
+                } else {
+                    Some(Rc::new(ClipChainNode {
+                        work_item: ClipWorkItem {
+                            scroll_node_data_index: prim_context.scroll_node.node_data_index,
+                            clip_sources: metadata.clip_sources.weak(),
+                            coordinate_system_id: prim_coordinate_system_id,
+                        },
+                        screen_inner_rect,
+                        combined_outer_screen_rect:
+                            combined_outer_rect.unwrap_or_else(DeviceIntRect::zero),
+                        combined_inner_screen_rect: DeviceIntRect::zero(),
+                        prev: None,
+                    }))
+                }
             } else {
                 None
             }
@@ -1460,6 +1500,16 @@ impl PrimitiveStore {
             })
             .collect();
 
+        if metadata.prim_kind == PrimitiveKind::TextRun {
+            let allow = clips.iter().all(|clip_item| {
+                let transform = &node_data[clip_item.scroll_node_data_index.0 as usize].transform;
+                clip_store
+                    .get_opt(&clip_item.clip_sources)
+                    .map_or(true, |clip_sources| clip_sources.allows_subpixel_aa(transform))
+            });
+            self.cpu_text_runs[metadata.cpu_prim_index.0].set_clips_allow_subpixel_aa(allow);
+        }
+
         if clips.is_empty() {
             // If this item is in the root coordinate system, then
             // we know that the local_clip_rect in the clip node
@@ -1494,7 +1544,7 @@ impl PrimitiveStore {
                         if clip_item.coordinate_system_id == prim_coordinate_system_id {
                             let local_clips = clip_store.get_opt(&clip_item.clip_sources).expect("bug");
                             let mut selected_clip = None;
-                            for &(ref clip, _) in &local_clips.clips {
+                            for &(ref clip, _) in local_clips.enabled_clips() {
                                 match *clip {
                                     ClipSource::RoundedRectangle(rect, radii, ClipMode::Clip) => {
                                         if selected_clip.is_some() {
@@ -1504,8 +1554,9 @@ impl PrimitiveStore {
                                         selected_clip = Some((rect, radii, clip_item.scroll_node_data_index));
                                     }
                                     ClipSource::Rectangle(..) => {}
-                                    ClipSource::RoundedRectangle(_, _, ClipMode::ClipOut) |
+                                    ClipSource::RoundedRectangle(..) |
                                     ClipSource::BorderCorner(..) |
+                                    ClipSource::RectWithHoles { .. } |
                                     ClipSource::Image(..) => {
                                         selected_clip = None;
                                         break;
@@ -1826,6 +1877,7 @@ impl PrimitiveStore {
 
             let child_prim_context = PrimitiveContext::new(
                 parent_prim_context.device_pixel_ratio,
+                parent_prim_context.max_clip_mask_texels,
                 display_list,
                 clip_node,
                 scroll_node,
@@ -1918,6 +1918,7 @@ impl Renderer {
             enable_scrollbars: options.enable_scrollbars,
             default_font_render_mode,
             debug: options.debug,
+            max_clip_mask_texels: options.max_clip_mask_texels,
         };
 
         let device_pixel_ratio = options.device_pixel_ratio;
@@ -4248,6 +4249,9 @@ pub struct RendererOptions {
     pub cached_programs: Option<Rc<ProgramCache>>,
     pub debug_flags: DebugFlags,
     pub renderer_id: Option<u64>,
+    /// The largest clip mask, in device pixels, that is drawn. Primitives
+    /// with larger masks are only clipped to their local clip rect.
+    pub max_clip_mask_texels: Option<u64>,
 }
 
 impl Default for RendererOptions {
@@ -4276,6 +4280,7 @@ impl Default for RendererOptions {
             enable_render_on_scroll: true,
             renderer_id: None,
             cached_programs: None,
+            max_clip_mask_texels: None,
         }
     }
 }
@@ -452,8 +452,9 @@ impl TextureCache {
 
         // Free the selected items
         for handle in eviction_candidates {
-            let entry = self.entries.free(handle);
-            self.free(entry);
+            if let Some(entry) = self.entries.free(handle) {
+                self.free(entry);
+            }
         }
 
         // Keep a record of the remaining handles for next frame.
@@ -506,10 +507,11 @@ impl TextureCache {
             if evicted_items > 512 && (found_matching_slab || freed_complete_page) {
                 retained_entries.push(handle);
             } else {
-                let entry = self.entries.free(handle);
-                if let Some(region) = self.free(entry) {
-                    found_matching_slab |= region.slab_size == needed_slab_size;
-                    freed_complete_page |= region.is_empty();
+                if let Some(entry) = self.entries.free(handle) {
+                    if let Some(region) = self.free(entry) {
+                        found_matching_slab |= region.slab_size == needed_slab_size;
+                        freed_complete_page |= region.is_empty();
+                    }
                 }
                 evicted_items += 1;
             }
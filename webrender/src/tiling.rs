@@ -10,7 +10,7 @@ use api::{LayerRect, MixBlendMode, PipelineId};
 use api::{TileOffset, YuvColorSpace, YuvFormat};
 use api::{LayerToWorldTransform, WorldPixel};
 use border::{BorderCornerInstance, BorderCornerSide};
-use clip::{ClipSource, ClipStore};
+use clip::{ClipSource, ClipStore, MAX_CLIP_HOLES};
 use clip_scroll_tree::{ClipScrollTree, CoordinateSystemId};
 use device::Texture;
 use euclid::{TypedTransform3D, vec3};
@@ -23,7 +23,8 @@ use internal_types::{FastHashMap, SourceTexture};
 use internal_types::{BatchTextures};
 use picture::{PictureCompositeMode, PictureKind, PicturePrimitive, RasterizationSpace};
 use plane_split::{BspSplitter, Polygon, Splitter};
-use prim_store::{PrimitiveIndex, PrimitiveKind, PrimitiveMetadata, PrimitiveStore};
+use prim_store::{CLIP_DATA_GPU_BLOCKS, PrimitiveIndex, PrimitiveKind, PrimitiveMetadata};
+use prim_store::PrimitiveStore;
 use prim_store::{BrushPrimitive, BrushMaskKind, BrushKind, BrushSegmentKind, DeferredResolve, PrimitiveRun};
 use profiler::FrameProfileCounters;
 use render_task::{ClipWorkItem};
@@ -1128,7 +1129,23 @@ impl ClipBatcher {
                 .get_opt(&work_item.clip_sources)
                 .expect("bug: clip handle should be valid");
 
-            for &(ref source, ref handle) in &info.clips {
+            // Chains of rectangles are drawn as their intersection, which is
+            // uploaded for the first of them.
+            if info.combined_rect().is_some() {
+                if work_item.coordinate_system_id != coordinate_system_id {
+                    if let Some(&(_, ref handle)) = info.enabled_clips().next() {
+                        self.rectangles.push(ClipMaskInstance {
+                            clip_data_address: gpu_cache.get_address(handle),
+                            ..instance
+                        });
+                    }
+                    coordinate_system_id = work_item.coordinate_system_id;
+                }
+                continue;
+            }
+
+            debug_assert!(info.all_images_requested(), "bug: image masks weren't requested");
+            for &(ref source, ref handle) in info.enabled_clips() {
                 let gpu_address = gpu_cache.get_address(handle);
 
                 match *source {
@@ -1162,6 +1179,16 @@ impl ClipBatcher {
                             ..instance
                         });
                     }
+                    ClipSource::RectWithHoles { ref holes, .. } => {
+                        // One rectangle instance for the rect, and one per hole.
+                        let rect_count = 1 + holes.len().min(MAX_CLIP_HOLES);
+                        for index in 0 .. rect_count {
+                            self.rectangles.push(ClipMaskInstance {
+                                clip_data_address: gpu_address + index * CLIP_DATA_GPU_BLOCKS,
+                                ..instance
+                            });
+                        }
+                    }
                     ClipSource::BorderCorner(ref source) => {
                         self.border_clears.push(ClipMaskInstance {
                             clip_data_address: gpu_address,
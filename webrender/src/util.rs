@@ -3,7 +3,8 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use api::{BorderRadius, DeviceIntPoint, DeviceIntRect, DeviceIntSize, DevicePoint, DeviceRect};
-use api::{DeviceSize, LayerPoint, LayerRect, LayerSize, LayerToWorldTransform, WorldRect};
+use api::{DeviceSize, LayerPoint, LayerRect, LayerSize, LayerToWorldTransform, WorldPoint};
+use api::WorldRect;
 use euclid::{Point2D, Rect, Size2D, TypedPoint2D, TypedRect, TypedSize2D, TypedTransform2D};
 use euclid::TypedTransform3D;
 use num_traits::Zero;
@@ -17,6 +18,7 @@ const NEARLY_ZERO: f32 = 1.0 / 4096.0;
 pub trait MatrixHelpers<Src, Dst> {
     fn preserves_2d_axis_alignment(&self) -> bool;
     fn has_perspective_component(&self) -> bool;
+    fn is_2d_translation(&self) -> bool;
     fn has_2d_inverse(&self) -> bool;
     fn inverse_project(&self, target: &TypedPoint2D<f32, Dst>) -> Option<TypedPoint2D<f32, Src>>;
     fn inverse_rect_footprint(&self, rect: &TypedRect<f32, Dst>) -> TypedRect<f32, Src>;
@@ -60,6 +62,12 @@ impl<Src, Dst> MatrixHelpers<Src, Dst> for TypedTransform3D<f32, Src, Dst> {
          self.m14 != 0.0 || self.m24 != 0.0 || self.m34 != 0.0 || self.m44 != 1.0
     }
 
+    // Whether points are only moved, neither scaled nor rotated.
+    fn is_2d_translation(&self) -> bool {
+        self.m11 == 1.0 && self.m12 == 0.0 && self.m21 == 0.0 && self.m22 == 1.0 &&
+            !self.has_perspective_component()
+    }
+
     fn has_2d_inverse(&self) -> bool {
         self.m11 * self.m22 - self.m12 * self.m21 != 0.0
     }
@@ -138,16 +146,52 @@ pub fn lerp(a: f32, b: f32, t: f32) -> f32 {
     (b - a) * t + a
 }
 
+// Transform a point, clamping the w component of points that end up behind
+// the camera of a perspective transform. Those would otherwise be projected
+// to the opposite side of the screen. With the clamp they're projected very
+// far away instead, in the direction they're going off screen.
+fn project_point_clamped(transform: &LayerToWorldTransform, point: &LayerPoint) -> WorldPoint {
+    let x = point.x * transform.m11 + point.y * transform.m21 + transform.m41;
+    let y = point.x * transform.m12 + point.y * transform.m22 + transform.m42;
+    let w = point.x * transform.m14 + point.y * transform.m24 + transform.m44;
+    let w = w.max(NEARLY_ZERO);
+
+    WorldPoint::new(x / w, y / w)
+}
+
+/// How a device space rect with fractional coordinates is mapped to whole
+/// device pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapMode {
+    /// Truncate the origin and size, which can leave out partially
+    /// covered pixels.
+    Truncate,
+    /// Round each edge to the nearest pixel.
+    Round,
+    /// Round the edges outwards, so that all the touched pixels are included.
+    /// This is what `calculate_screen_bounding_rect` does.
+    Expand,
+}
+
 pub fn calculate_screen_bounding_rect(
     transform: &LayerToWorldTransform,
     rect: &LayerRect,
     device_pixel_ratio: f32
+) -> DeviceIntRect {
+    calculate_screen_bounding_rect_snapped(transform, rect, device_pixel_ratio, SnapMode::Expand)
+}
+
+pub fn calculate_screen_bounding_rect_snapped(
+    transform: &LayerToWorldTransform,
+    rect: &LayerRect,
+    device_pixel_ratio: f32,
+    snap_mode: SnapMode,
 ) -> DeviceIntRect {
     let rect = WorldRect::from_points(&[
-        transform.transform_point2d(&rect.origin),
-        transform.transform_point2d(&rect.top_right()),
-        transform.transform_point2d(&rect.bottom_left()),
-        transform.transform_point2d(&rect.bottom_right()),
+        project_point_clamped(transform, &rect.origin),
+        project_point_clamped(transform, &rect.top_right()),
+        project_point_clamped(transform, &rect.bottom_left()),
+        project_point_clamped(transform, &rect.bottom_right()),
     ]) * device_pixel_ratio;
 
     let rect = DeviceRect::new(
@@ -155,8 +199,56 @@ pub fn calculate_screen_bounding_rect(
         DeviceSize::new(rect.size.width, rect.size.height),
     );
 
+    let rect = match snap_mode {
+        SnapMode::Truncate => rect,
+        SnapMode::Round => {
+            let (x0, y0) = (rect.min_x().round(), rect.min_y().round());
+            let (x1, y1) = (rect.max_x().round(), rect.max_y().round());
+            DeviceRect::new(DevicePoint::new(x0, y0), DeviceSize::new(x1 - x0, y1 - y0))
+        }
+        SnapMode::Expand => rect.round_out(),
+    };
+
     let max_rect = DeviceRect::max_rect();
-    rect.round_out().intersection(&max_rect).unwrap_or(max_rect).to_i32()
+    rect.intersection(&max_rect).unwrap_or(max_rect).to_i32()
+}
+
+/// The device space bounds of a local rect, along with the device space
+/// bounds of a local inner rect that lies inside of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransformedRect {
+    pub bounding_rect: DeviceIntRect,
+    pub inner_rect: DeviceIntRect,
+}
+
+impl TransformedRect {
+    pub fn new(
+        transform: &LayerToWorldTransform,
+        rect: &LayerRect,
+        inner_rect: &LayerRect,
+        device_pixel_ratio: f32,
+        snap_mode: SnapMode,
+    ) -> TransformedRect {
+        let bounding_rect =
+            calculate_screen_bounding_rect_snapped(transform, rect, device_pixel_ratio, snap_mode);
+        let inner_rect = calculate_screen_bounding_rect_snapped(
+            transform,
+            inner_rect,
+            device_pixel_ratio,
+            snap_mode,
+        );
+
+        // Snapping the edges of a thin inner rect independently of the outer
+        // rect can move them past each other, or past the outer edges.
+        let inner_rect = inner_rect
+            .intersection(&bounding_rect)
+            .unwrap_or(DeviceIntRect::zero());
+
+        TransformedRect {
+            bounding_rect,
+            inner_rect,
+        }
+    }
 }
 
 pub fn _subtract_rect<U>(
@@ -230,6 +322,17 @@ fn extract_inner_rect_impl<U>(
     let yb =
         (rect.size.height - k * radii.bottom_left.height.max(radii.bottom_right.height)).floor();
 
+    inner_rect_from_offsets(rect, xl, xr, yt, yb)
+}
+
+#[inline]
+fn inner_rect_from_offsets<U>(
+    rect: &TypedRect<f32, U>,
+    xl: f32,
+    xr: f32,
+    yt: f32,
+    yb: f32,
+) -> Option<TypedRect<f32, U>> {
     if xl <= xr && yt <= yb {
         Some(TypedRect::new(
             TypedPoint2D::new(rect.origin.x + xl, rect.origin.y + yt),
@@ -246,6 +349,28 @@ pub fn extract_inner_rect_safe<U>(
     rect: &TypedRect<f32, U>,
     radii: &BorderRadius,
 ) -> Option<TypedRect<f32, U>> {
+    // The common symmetric cases need fewer comparisons. They give exactly
+    // the same result as the general case below.
+    if let Some(radius) = radii.is_uniform_size() {
+        return inner_rect_from_offsets(
+            rect,
+            radius.width.ceil(),
+            (rect.size.width - radius.width).floor(),
+            radius.height.ceil(),
+            (rect.size.height - radius.height).floor(),
+        );
+    }
+    if radii.top_left == radii.top_right && radii.bottom_left == radii.bottom_right {
+        let width = radii.top_left.width.max(radii.bottom_left.width);
+        return inner_rect_from_offsets(
+            rect,
+            width.ceil(),
+            (rect.size.width - width).floor(),
+            radii.top_left.height.ceil(),
+            (rect.size.height - radii.bottom_left.height).floor(),
+        );
+    }
+
     // value of `k==1.0` is used for extraction of the corner rectangles
     // see `SEGMENT_CORNER_*` in `clip_shared.glsl`
     extract_inner_rect_impl(rect, radii, 1.0)
@@ -273,6 +398,31 @@ pub mod test {
     use euclid::{Point2D, Radians, Transform3D};
     use std::f32::consts::PI;
 
+    #[test]
+    fn symmetric_inner_rects_match_general_case() {
+        let rects = [
+            LayerRect::new(LayerPoint::new(0.0, 0.0), LayerSize::new(100.0, 50.0)),
+            LayerRect::new(LayerPoint::new(0.5, 1.25), LayerSize::new(20.5, 10.75)),
+            LayerRect::new(LayerPoint::new(0.0, 0.0), LayerSize::new(10.0, 10.0)),
+        ];
+        let top = LayerSize::new(4.5, 2.0);
+        let bottom = LayerSize::new(3.0, 6.25);
+        let radii = [
+            BorderRadius::uniform(4.5),
+            BorderRadius::uniform_size(LayerSize::new(8.0, 3.5)),
+            BorderRadius { top_left: top, top_right: top, bottom_left: bottom, bottom_right: bottom },
+            BorderRadius::uniform(20.0),
+        ];
+        for rect in &rects {
+            for radii in &radii {
+                assert_eq!(
+                    extract_inner_rect_safe(rect, radii),
+                    extract_inner_rect_impl(rect, radii, 1.0)
+                );
+            }
+        }
+    }
+
     #[test]
     fn inverse_project() {
         let m0 = Transform3D::identity();
@@ -283,6 +433,65 @@ pub mod test {
         // rotation by 60 degrees would imply scaling of X component by a factor of 2
         assert_eq!(m1.inverse_project(&p0), Some(Point2D::new(2.0, 2.0)));
     }
+
+    #[test]
+    fn screen_bounding_rect_behind_camera() {
+        let transform = LayerToWorldTransform::create_perspective(100.0)
+            .pre_mul(&LayerToWorldTransform::create_rotation(0.0, 1.0, 0.0, Radians::new(PI / 3.0)));
+        let rect = LayerRect::new(LayerPoint::new(-200.0, -10.0), LayerSize::new(400.0, 20.0));
+
+        // One side of the rect is behind the camera, the other isn't.
+        let w = |point: LayerPoint| point.x * transform.m14 + point.y * transform.m24 + transform.m44;
+        assert!(w(rect.origin) > 0.0 || w(rect.top_right()) > 0.0);
+        assert!(w(rect.origin) < 0.0 || w(rect.top_right()) < 0.0);
+
+        let device_rect = calculate_screen_bounding_rect(&transform, &rect, 1.0);
+        assert!(DeviceIntRect::max_rect().contains_rect(&device_rect));
+        assert!(device_rect.size.width > 0 && device_rect.size.height > 0);
+
+        // The visible side of the rect is still in the bounds.
+        let visible = if w(rect.origin) > 0.0 { rect.origin } else { rect.top_right() };
+        let visible = transform.transform_point2d(&visible);
+        assert!(device_rect.min_x() as f32 <= visible.x && visible.x <= device_rect.max_x() as f32);
+        assert!(device_rect.min_y() as f32 <= visible.y && visible.y <= device_rect.max_y() as f32);
+    }
+
+    #[test]
+    fn screen_bounding_rect_snap_modes() {
+        let rect = LayerRect::new(LayerPoint::new(0.25, 0.75), LayerSize::new(10.5, 10.0));
+        let transform = LayerToWorldTransform::identity();
+        let snapped = |snap_mode| {
+            calculate_screen_bounding_rect_snapped(&transform, &rect, 1.0, snap_mode)
+        };
+        let device_rect = |x, y, w, h| {
+            DeviceIntRect::new(DeviceIntPoint::new(x, y), DeviceIntSize::new(w, h))
+        };
+
+        assert_eq!(snapped(SnapMode::Truncate), device_rect(0, 0, 10, 10));
+        assert_eq!(snapped(SnapMode::Round), device_rect(0, 1, 11, 10));
+        assert_eq!(snapped(SnapMode::Expand), device_rect(0, 0, 11, 11));
+        assert_eq!(
+            calculate_screen_bounding_rect(&transform, &rect, 1.0),
+            snapped(SnapMode::Expand)
+        );
+    }
+
+    #[test]
+    fn transformed_rect_of_thin_rect() {
+        let transform = LayerToWorldTransform::create_rotation(0.0, 0.0, 1.0, Radians::new(0.1));
+        let outer = LayerRect::new(LayerPoint::new(10.0, 10.0), LayerSize::new(1.0, 50.0));
+        for &width in &[1.0, 0.2, 0.0] {
+            let inner = LayerRect::new(LayerPoint::new(10.3, 10.0), LayerSize::new(width, 50.0));
+            for &snap_mode in &[SnapMode::Truncate, SnapMode::Round, SnapMode::Expand] {
+                let rect = TransformedRect::new(&transform, &outer, &inner, 1.0, snap_mode);
+                let inner_rect = rect.inner_rect;
+                assert!(inner_rect.size.width >= 0 && inner_rect.size.height >= 0);
+                assert!(
+                    rect_is_empty(&inner_rect) || rect.bounding_rect.contains_rect(&inner_rect)
+                );
+            }
+        }
+    }
 }
 
 pub trait MaxRect {
@@ -3,7 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use api::{Epoch, PipelineId};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Cursor, Read};
 use std::mem;
 
@@ -21,16 +21,17 @@ pub struct Payload {
 }
 
 impl Payload {
+    /// The size of the epoch, pipeline id and display list length that
+    /// precede the display list data in a serialized payload.
+    pub const HEADER_SIZE: usize = 3 * mem::size_of::<u32>() + mem::size_of::<u64>();
+
     /// Convert the payload to a raw byte vector, in order for it to be
     /// efficiently shared via shmem, for example.
     ///
     /// TODO(emilio, #1049): Consider moving the IPC boundary to the
     /// constellation in Servo and remove this complexity from WR.
     pub fn to_data(&self) -> Vec<u8> {
-        let mut data = Vec::with_capacity(
-            mem::size_of::<u32>() + 2 * mem::size_of::<u32>() + mem::size_of::<u64>() +
-                self.display_list_data.len(),
-        );
+        let mut data = Vec::with_capacity(Payload::HEADER_SIZE + self.display_list_data.len());
         data.write_u32::<LittleEndian>(self.epoch.0).unwrap();
         data.write_u32::<LittleEndian>(self.pipeline_id.0).unwrap();
         data.write_u32::<LittleEndian>(self.pipeline_id.1).unwrap();
@@ -63,6 +64,16 @@ impl Payload {
             display_list_data: built_display_list_data,
         }
     }
+
+    /// Whether `data` holds a whole serialized payload, or just the start
+    /// of one. Large display lists may arrive split over several chunks.
+    pub fn is_complete(data: &[u8]) -> bool {
+        if data.len() < Payload::HEADER_SIZE {
+            return false;
+        }
+        let dl_size = LittleEndian::read_u64(&data[12 .. Payload::HEADER_SIZE]) as usize;
+        data.len() >= Payload::HEADER_SIZE + dl_size
+    }
 }
 
 
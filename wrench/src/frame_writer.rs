@@ -0,0 +1,432 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// State shared by the frame writers that serialize whole display lists
+// (RON and JSON): the resources referenced by the frames, and the layout
+// of a written frame.
+
+use app_units::Au;
+use std::borrow::BorrowMut;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use webrender::api::*;
+
+/// Problems that frame writers work around, by skipping the affected
+/// resource or frame, rather than stopping the capture.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriterError {
+    /// Blob images are rasterized by the embedder, so only their tiling
+    /// is recorded, not their contents.
+    SkippedBlobImage(ImageKey),
+    /// An update changed the size or format of an image.
+    UpdateSizeMismatch {
+        key: ImageKey,
+        old: ImageDescriptor,
+        new: ImageDescriptor,
+    },
+    /// An image was updated without having been added, most likely because
+    /// the capture started mid-session. Raw updates are kept as the initial
+    /// upload, any others are skipped.
+    UnknownImageUpdate(ImageKey),
+    /// Streaming an image to its file failed, so it is kept in memory.
+    ImageWriteFailed { key: ImageKey, message: String },
+    SerializeFailed(String),
+    /// Writing a file of the capture failed, so it is missing.
+    WriteFailed { path: PathBuf, message: String },
+}
+
+impl fmt::Display for WriterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriterError::SkippedBlobImage(key) => {
+                write!(f, "Skipped the contents of blob image {:?}, only its tiling is kept", key)
+            }
+            WriterError::UpdateSizeMismatch { key, ref old, ref new } => write!(
+                f,
+                "Skipped update of image {:?} from {}x{} {:?} to {}x{} {:?}",
+                key,
+                old.width,
+                old.height,
+                old.format,
+                new.width,
+                new.height,
+                new.format
+            ),
+            WriterError::UnknownImageUpdate(key) => {
+                write!(f, "Update of unknown image {:?}, capture started mid-session?", key)
+            }
+            WriterError::ImageWriteFailed { key, ref message } => {
+                write!(f, "Failed to write image {:?} ({}), keeping it in memory", key, message)
+            }
+            WriterError::SerializeFailed(ref message) => {
+                write!(f, "Failed to serialize frame: {}", message)
+            }
+            WriterError::WriteFailed { ref path, ref message } => {
+                write!(f, "Failed to write {} ({})", path.display(), message)
+            }
+        }
+    }
+}
+
+/// The error sink of writers that haven't been given one.
+pub fn print_error(error: WriterError) {
+    eprintln!("{}", error);
+}
+
+pub enum CachedFont {
+    Native(NativeFontHandle),
+    Raw(Option<Vec<u8>>, u32, Option<PathBuf>),
+}
+
+#[allow(dead_code)]
+pub struct CachedFontInstance {
+    pub font_key: FontKey,
+    pub glyph_size: Au,
+}
+
+/// The texture an external image is read from when the frame is replayed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ExternalImage {
+    pub id: ExternalImageId,
+    pub channel_index: u8,
+}
+
+impl ExternalImage {
+    fn new(data: &ExternalImageData) -> Self {
+        ExternalImage {
+            id: data.id,
+            channel_index: data.channel_index,
+        }
+    }
+}
+
+/// How a blob image is rasterized, which the replaying blob renderer needs
+/// to know.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BlobImage {
+    pub tile_size: Option<TileSize>,
+    /// The part of the image changed by the last update, or `None` if all
+    /// of it has to be rasterized.
+    pub dirty_rect: Option<DeviceUintRect>,
+    /// The tiles overlapping the dirty rect, for tiled images.
+    pub dirty_tiles: Option<Vec<TileOffset>>,
+}
+
+impl BlobImage {
+    fn new(tile_size: Option<TileSize>) -> Self {
+        BlobImage {
+            tile_size,
+            dirty_rect: None,
+            dirty_tiles: None,
+        }
+    }
+
+    fn set_dirty_rect(&mut self, dirty_rect: Option<DeviceUintRect>) {
+        self.dirty_rect = dirty_rect;
+        self.dirty_tiles = match (self.tile_size, dirty_rect) {
+            (Some(tile_size), Some(rect)) if tile_size > 0 && rect.size.width > 0 &&
+                rect.size.height > 0 => {
+                let tile_size = tile_size as u32;
+                let (x0, y0) = (rect.origin.x / tile_size, rect.origin.y / tile_size);
+                let (x1, y1) = ((rect.max_x() - 1) / tile_size, (rect.max_y() - 1) / tile_size);
+                let mut tiles = Vec::new();
+                for y in y0 .. y1 + 1 {
+                    for x in x0 .. x1 + 1 {
+                        tiles.push(TileOffset::new(x as u16, y as u16));
+                    }
+                }
+                Some(tiles)
+            }
+            (Some(_), Some(_)) => Some(Vec::new()),
+            _ => None,
+        };
+    }
+}
+
+// `ImageFormat` has no indexed (palettized) formats, so the bytes always
+// describe the pixels completely, and there is no palette to capture. If
+// such a format gets added, its palette needs to be recorded here as well,
+// or the written frames can't be replayed.
+pub struct CachedImage {
+    pub descriptor: ImageDescriptor,
+    pub bytes: Option<Vec<u8>>,
+    pub path: Option<PathBuf>,
+    /// Set for external images, which have no bytes of their own.
+    pub external: Option<ExternalImage>,
+    /// Set for blob images, whose bytes aren't recorded.
+    pub blob: Option<BlobImage>,
+}
+
+/// The resources known to the recorded API, as of the frame being written.
+pub struct FrameResources {
+    pub images: HashMap<ImageKey, CachedImage>,
+    // Fonts are tracked, but not written out with the frames yet.
+    #[allow(dead_code)]
+    pub fonts: HashMap<FontKey, CachedFont>,
+    #[allow(dead_code)]
+    pub font_instances: HashMap<FontInstanceKey, CachedFontInstance>,
+}
+
+impl FrameResources {
+    pub fn new() -> Self {
+        FrameResources {
+            images: HashMap::new(),
+            fonts: HashMap::new(),
+            font_instances: HashMap::new(),
+        }
+    }
+
+    /// The descriptors of all the known images, in key order.
+    pub fn image_descriptors(&self) -> Vec<(ImageKey, ImageDescriptor)> {
+        let mut images: Vec<_> = self.images
+            .iter()
+            .map(|(key, image)| (*key, image.descriptor))
+            .collect();
+        images.sort_by_key(|&(key, _)| (key.0 .0, key.1));
+        images
+    }
+
+    /// The external images, in key order.
+    pub fn external_images(&self) -> Vec<(ImageKey, ExternalImage)> {
+        let mut images: Vec<_> = self.images
+            .iter()
+            .filter_map(|(key, image)| image.external.map(|external| (*key, external)))
+            .collect();
+        images.sort_by_key(|&(key, _)| (key.0 .0, key.1));
+        images
+    }
+
+    /// The blob images, in key order.
+    pub fn blob_images(&self) -> Vec<(ImageKey, BlobImage)> {
+        let mut images: Vec<_> = self.images
+            .iter()
+            .filter_map(|(key, image)| image.blob.clone().map(|blob| (*key, blob)))
+            .collect();
+        images.sort_by_key(|&(key, _)| (key.0 .0, key.1));
+        images
+    }
+
+    pub fn update(&mut self, updates: &ResourceUpdates, errors: &mut FnMut(WriterError)) {
+        for update in &updates.updates {
+            match *update {
+                ResourceUpdate::AddImage(ref img) => {
+                    let (bytes, external, blob) = match img.data {
+                        ImageData::Raw(ref v) => (Some((**v).clone()), None, None),
+                        ImageData::External(ref data) => {
+                            (None, Some(ExternalImage::new(data)), None)
+                        }
+                        ImageData::Blob(_) => {
+                            errors(WriterError::SkippedBlobImage(img.key));
+                            (None, None, Some(BlobImage::new(img.tiling)))
+                        }
+                    };
+                    self.images.insert(
+                        img.key,
+                        CachedImage {
+                            descriptor: img.descriptor,
+                            bytes,
+                            path: None,
+                            external,
+                            blob,
+                        },
+                    );
+                }
+                ResourceUpdate::UpdateImage(ref img) => {
+                    if let Some(ref mut data) = self.images.get_mut(&img.key) {
+                        if data.descriptor.width != img.descriptor.width ||
+                            data.descriptor.height != img.descriptor.height ||
+                            data.descriptor.format != img.descriptor.format
+                        {
+                            errors(WriterError::UpdateSizeMismatch {
+                                key: img.key,
+                                old: data.descriptor,
+                                new: img.descriptor,
+                            });
+                            continue;
+                        }
+
+                        if let ImageData::Raw(ref bytes) = img.data {
+                            data.descriptor = img.descriptor;
+                            *data.path.borrow_mut() = None;
+                            *data.bytes.borrow_mut() = Some((**bytes).clone());
+                            data.external = None;
+                            data.blob = None;
+                        } else if let ImageData::External(ref external) = img.data {
+                            data.descriptor = img.descriptor;
+                            data.path = None;
+                            data.bytes = None;
+                            data.external = Some(ExternalImage::new(external));
+                            data.blob = None;
+                        } else if let Some(ref mut blob) = data.blob {
+                            data.descriptor = img.descriptor;
+                            blob.set_dirty_rect(img.dirty_rect);
+                            errors(WriterError::SkippedBlobImage(img.key));
+                        } else {
+                            // Other existing image types only make sense within the gecko integration.
+                            errors(WriterError::SkippedBlobImage(img.key));
+                        }
+                        continue;
+                    }
+
+                    // The image was added before the capture started, so the best
+                    // we can do is to treat the update as the initial upload.
+                    errors(WriterError::UnknownImageUpdate(img.key));
+                    if let ImageData::Raw(ref bytes) = img.data {
+                        self.images.insert(
+                            img.key,
+                            CachedImage {
+                                descriptor: img.descriptor,
+                                bytes: Some((**bytes).clone()),
+                                path: None,
+                                external: None,
+                                blob: None,
+                            },
+                        );
+                    }
+                }
+                ResourceUpdate::DeleteImage(img) => {
+                    self.images.remove(&img);
+                }
+                ResourceUpdate::AddFont(ref font) => match font {
+                    &AddFont::Raw(key, ref bytes, index) => {
+                        self.fonts
+                            .insert(key, CachedFont::Raw(Some(bytes.clone()), index, None));
+                    }
+                    &AddFont::Native(key, ref handle) => {
+                        self.fonts.insert(key, CachedFont::Native(handle.clone()));
+                    }
+                },
+                ResourceUpdate::DeleteFont(_) => {}
+                ResourceUpdate::AddFontInstance(ref instance) => {
+                    self.font_instances.insert(
+                        instance.key,
+                        CachedFontInstance {
+                            font_key: instance.font_key,
+                            glyph_size: instance.glyph_size,
+                        },
+                    );
+                }
+                ResourceUpdate::DeleteFontInstance(_) => {}
+            }
+        }
+    }
+}
+
+/// Statistics about the display list of a frame, recorded along with it
+/// in order to track display list building performance.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FrameHeader {
+    pub item_count: usize,
+    /// The number of items that draw something, i.e. leaving out the
+    /// ones that only set up clips, stacking contexts and such.
+    pub drawing_item_count: usize,
+    pub display_list_bytes: usize,
+}
+
+impl FrameHeader {
+    pub fn new(dl: &BuiltDisplayList) -> Self {
+        let mut item_count = 0;
+        let mut drawing_item_count = 0;
+        let mut traversal = dl.iter();
+        while let Some(item) = traversal.next() {
+            item_count += 1;
+            match *item.item() {
+                SpecificDisplayItem::Clip(..) |
+                SpecificDisplayItem::ScrollFrame(..) |
+                SpecificDisplayItem::StickyFrame(..) |
+                SpecificDisplayItem::ClearRectangle |
+                SpecificDisplayItem::PushStackingContext(..) |
+                SpecificDisplayItem::PopStackingContext |
+                SpecificDisplayItem::SetGradientStops |
+                SpecificDisplayItem::PushShadow(..) |
+                SpecificDisplayItem::PopAllShadows => {}
+                _ => drawing_item_count += 1,
+            }
+        }
+
+        FrameHeader {
+            item_count,
+            drawing_item_count,
+            display_list_bytes: dl.data().len(),
+        }
+    }
+}
+
+/// The pan and zoom state of the document, which is set by separate
+/// messages rather than with the display list.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct DocumentView {
+    pub page_zoom: f32,
+    pub pinch_zoom: f32,
+    pub pan: DeviceIntPoint,
+}
+
+impl DocumentView {
+    pub fn new() -> Self {
+        DocumentView {
+            page_zoom: 1.0,
+            pinch_zoom: 1.0,
+            pan: DeviceIntPoint::zero(),
+        }
+    }
+
+    pub fn update(&mut self, msg: &DocumentMsg) {
+        match *msg {
+            DocumentMsg::SetPageZoom(factor) => self.page_zoom = factor.get(),
+            DocumentMsg::SetPinchZoom(factor) => self.pinch_zoom = factor.get(),
+            DocumentMsg::SetPan(pan) => self.pan = pan,
+            _ => {}
+        }
+    }
+}
+
+/// The version of the `Frame` layout that the RON and JSON writers use.
+/// Frames without a `format_version` are version 0, which only held the
+/// display list at the top level.
+pub const FRAME_FORMAT_VERSION: u32 = 1;
+
+/// What gets written for each frame.
+#[derive(Serialize)]
+pub struct Frame<'a> {
+    pub format_version: u32,
+    pub header: FrameHeader,
+    pub pipeline_id: PipelineId,
+    pub epoch: Epoch,
+    pub viewport_size: LayoutSize,
+    /// The scrollable size of the pipeline's content, which limits scrolling
+    /// when the frame is replayed.
+    pub content_size: LayoutSize,
+    pub view: DocumentView,
+    pub images: Vec<(ImageKey, ImageDescriptor)>,
+    pub external_images: Vec<(ImageKey, ExternalImage)>,
+    pub blob_images: Vec<(ImageKey, BlobImage)>,
+    pub display_list: &'a BuiltDisplayList,
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(
+        dl: &'a BuiltDisplayList,
+        resources: &FrameResources,
+        pipeline_id: PipelineId,
+        epoch: Epoch,
+        viewport_size: LayoutSize,
+        content_size: LayoutSize,
+        view: DocumentView,
+    ) -> Self {
+        Frame {
+            format_version: FRAME_FORMAT_VERSION,
+            header: FrameHeader::new(dl),
+            pipeline_id,
+            epoch,
+            viewport_size,
+            content_size,
+            view,
+            images: resources.image_descriptors(),
+            external_images: resources.external_images(),
+            blob_images: resources.blob_images(),
+            display_list: dl,
+        }
+    }
+}
@@ -6,52 +6,33 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
 
-use app_units::Au;
+use frame_writer::{DocumentView, Frame, FrameResources, print_error};
 use image::{save_buffer, ColorType};
 use premultiply::unpremultiply;
 use serde_json;
-use std::borrow::BorrowMut;
-use std::collections::HashMap;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::{fmt, fs};
+use std::{fmt, fs, mem};
 use super::CURRENT_FRAME_NUMBER;
 use time;
 use webrender;
 use webrender::api::*;
 use webrender::api::channel::Payload;
 
-enum CachedFont {
-    Native(NativeFontHandle),
-    Raw(Option<Vec<u8>>, u32, Option<PathBuf>),
-}
-
-struct CachedFontInstance {
-    font_key: FontKey,
-    glyph_size: Au,
-}
-
-struct CachedImage {
-    width: u32,
-    height: u32,
-    stride: u32,
-    format: ImageFormat,
-    bytes: Option<Vec<u8>>,
-    path: Option<PathBuf>,
-}
-
 pub struct JsonFrameWriter {
     frame_base: PathBuf,
     rsrc_base: PathBuf,
     rsrc_prefix: String,
     next_rsrc_num: u32,
-    images: HashMap<ImageKey, CachedImage>,
-    fonts: HashMap<FontKey, CachedFont>,
-    font_instances: HashMap<FontInstanceKey, CachedFontInstance>,
+    resources: FrameResources,
 
     last_frame_written: u32,
 
     dl_descriptor: Option<BuiltDisplayListDescriptor>,
+    pending_payload: Vec<u8>,
+    viewport_size: LayoutSize,
+    content_size: LayoutSize,
+    view: DocumentView,
 }
 
 impl JsonFrameWriter {
@@ -67,11 +48,13 @@ impl JsonFrameWriter {
             rsrc_base,
             rsrc_prefix,
             next_rsrc_num: 1,
-            images: HashMap::new(),
-            fonts: HashMap::new(),
-            font_instances: HashMap::new(),
+            resources: FrameResources::new(),
 
             dl_descriptor: None,
+            pending_payload: Vec::new(),
+            viewport_size: LayoutSize::zero(),
+            content_size: LayoutSize::zero(),
+            view: DocumentView::new(),
 
             last_frame_written: u32::max_value(),
         }
@@ -82,7 +65,8 @@ impl JsonFrameWriter {
         _: &Epoch,
         _: &PipelineId,
         _: &Option<ColorF>,
-        _: &LayoutSize,
+        viewport_size: &LayoutSize,
+        content_size: &LayoutSize,
         display_list: &BuiltDisplayListDescriptor,
     ) {
         unsafe {
@@ -93,10 +77,14 @@ impl JsonFrameWriter {
         }
 
         self.dl_descriptor = Some(display_list.clone());
+        self.pending_payload.clear();
+        self.viewport_size = *viewport_size;
+        self.content_size = *content_size;
     }
 
     pub fn finish_write_display_list(&mut self, frame: u32, data: &[u8]) {
         let payload = Payload::from_data(data);
+        let (pipeline_id, epoch) = (payload.pipeline_id, payload.epoch);
         let dl_desc = self.dl_descriptor.take().unwrap();
 
         let dl = BuiltDisplayList::from_data(payload.display_list_data, dl_desc);
@@ -107,79 +95,22 @@ impl JsonFrameWriter {
 
         let mut file = fs::File::create(&frame_file_name).unwrap();
 
-        let s = serde_json::to_string_pretty(&dl).unwrap();
+        let frame = Frame::new(
+            &dl,
+            &self.resources,
+            pipeline_id,
+            epoch,
+            self.viewport_size,
+            self.content_size,
+            self.view,
+        );
+        let s = serde_json::to_string_pretty(&frame).unwrap();
         file.write_all(&s.into_bytes()).unwrap();
         file.write_all(b"\n").unwrap();
     }
 
     fn update_resources(&mut self, updates: &ResourceUpdates) {
-        for update in &updates.updates {
-            match *update {
-                ResourceUpdate::AddImage(ref img) => {
-                    let stride = img.descriptor.stride.unwrap_or(
-                        img.descriptor.width * img.descriptor.format.bytes_per_pixel(),
-                    );
-                    let bytes = match img.data {
-                        ImageData::Raw(ref v) => (**v).clone(),
-                        ImageData::External(_) | ImageData::Blob(_) => {
-                            return;
-                        }
-                    };
-                    self.images.insert(
-                        img.key,
-                        CachedImage {
-                            width: img.descriptor.width,
-                            height: img.descriptor.height,
-                            stride,
-                            format: img.descriptor.format,
-                            bytes: Some(bytes),
-                            path: None,
-                        },
-                    );
-                }
-                ResourceUpdate::UpdateImage(ref img) => {
-                    if let Some(ref mut data) = self.images.get_mut(&img.key) {
-                        assert_eq!(data.width, img.descriptor.width);
-                        assert_eq!(data.height, img.descriptor.height);
-                        assert_eq!(data.format, img.descriptor.format);
-
-                        if let ImageData::Raw(ref bytes) = img.data {
-                            *data.path.borrow_mut() = None;
-                            *data.bytes.borrow_mut() = Some((**bytes).clone());
-                        } else {
-                            // Other existing image types only make sense within the gecko integration.
-                            println!(
-                                "Wrench only supports updating buffer images ({}).",
-                                "ignoring update commands"
-                            );
-                        }
-                    }
-                }
-                ResourceUpdate::DeleteImage(img) => {
-                    self.images.remove(&img);
-                }
-                ResourceUpdate::AddFont(ref font) => match font {
-                    &AddFont::Raw(key, ref bytes, index) => {
-                        self.fonts
-                            .insert(key, CachedFont::Raw(Some(bytes.clone()), index, None));
-                    }
-                    &AddFont::Native(key, ref handle) => {
-                        self.fonts.insert(key, CachedFont::Native(handle.clone()));
-                    }
-                },
-                ResourceUpdate::DeleteFont(_) => {}
-                ResourceUpdate::AddFontInstance(ref instance) => {
-                    self.font_instances.insert(
-                        instance.key,
-                        CachedFontInstance {
-                            font_key: instance.font_key,
-                            glyph_size: instance.glyph_size,
-                        },
-                    );
-                }
-                ResourceUpdate::DeleteFontInstance(_) => {}
-            }
-        }
+        self.resources.update(updates, &mut print_error);
     }
 
     fn next_rsrc_paths(
@@ -202,7 +133,7 @@ impl JsonFrameWriter {
     }
 
     fn path_for_image(&mut self, key: ImageKey) -> Option<PathBuf> {
-        if let Some(ref mut data) = self.images.get_mut(&key) {
+        if let Some(ref mut data) = self.resources.images.get_mut(&key) {
             if data.path.is_some() {
                 return data.path.clone();
             }
@@ -211,7 +142,7 @@ impl JsonFrameWriter {
         };
 
         // Remove the data to munge it
-        let mut data = self.images.remove(&key).unwrap();
+        let mut data = self.resources.images.remove(&key).unwrap();
         let mut bytes = data.bytes.take().unwrap();
         let (path_file, path) = Self::next_rsrc_paths(
             &self.rsrc_prefix,
@@ -221,38 +152,42 @@ impl JsonFrameWriter {
             "png",
         );
 
-        let ok = match data.format {
-            ImageFormat::RGB8 => if data.stride == data.width * 3 {
+        let (width, height) = (data.descriptor.width, data.descriptor.height);
+        let stride = data.descriptor
+            .stride
+            .unwrap_or(width * data.descriptor.format.bytes_per_pixel());
+        let ok = match data.descriptor.format {
+            ImageFormat::RGB8 => if stride == width * 3 {
                 save_buffer(
                     &path_file,
                     &bytes,
-                    data.width,
-                    data.height,
+                    width,
+                    height,
                     ColorType::RGB(8),
                 ).unwrap();
                 true
             } else {
                 false
             },
-            ImageFormat::BGRA8 => if data.stride == data.width * 4 {
+            ImageFormat::BGRA8 => if stride == width * 4 {
                 unpremultiply(bytes.as_mut_slice());
                 save_buffer(
                     &path_file,
                     &bytes,
-                    data.width,
-                    data.height,
+                    width,
+                    height,
                     ColorType::RGBA(8),
                 ).unwrap();
                 true
             } else {
                 false
             },
-            ImageFormat::A8 => if data.stride == data.width {
+            ImageFormat::A8 => if stride == width {
                 save_buffer(
                     &path_file,
                     &bytes,
-                    data.width,
-                    data.height,
+                    width,
+                    height,
                     ColorType::Gray(8),
                 ).unwrap();
                 true
@@ -265,17 +200,17 @@ impl JsonFrameWriter {
         if !ok {
             println!(
                 "Failed to write image with format {:?}, dimensions {}x{}, stride {}",
-                data.format,
-                data.width,
-                data.height,
-                data.stride
+                data.descriptor.format,
+                width,
+                height,
+                stride
             );
             return None;
         }
 
         data.path = Some(path.clone());
         // put it back
-        self.images.insert(key, data);
+        self.resources.images.insert(key, data);
         Some(path)
     }
 }
@@ -298,6 +233,7 @@ impl webrender::ApiRecordingReceiver for JsonFrameWriter {
                     ref pipeline_id,
                     ref background,
                     ref viewport_size,
+                    ref content_size,
                     ref list_descriptor,
                     ref resources,
                     ..
@@ -309,17 +245,124 @@ impl webrender::ApiRecordingReceiver for JsonFrameWriter {
                     pipeline_id,
                     background,
                     viewport_size,
+                    content_size,
                     list_descriptor,
                 );
             }
+            ApiMsg::UpdateDocument(_, ref msg) => self.view.update(msg),
             ApiMsg::CloneApi(..) => {}
             _ => {}
         }
     }
 
     fn write_payload(&mut self, frame: u32, data: &[u8]) {
-        if self.dl_descriptor.is_some() {
-            self.finish_write_display_list(frame, data);
+        if self.dl_descriptor.is_none() {
+            return;
+        }
+
+        // Hold on to the chunks of a large payload until the whole display
+        // list is there.
+        self.pending_payload.extend_from_slice(data);
+        if Payload::is_complete(&self.pending_payload) {
+            let payload = mem::replace(&mut self.pending_payload, Vec::new());
+            self.finish_write_display_list(frame, &payload);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FRAME_LOCK;
+    use frame_writer::FRAME_FORMAT_VERSION;
+    use webrender::ApiRecordingReceiver;
+
+    #[test]
+    fn frame_parses_back_as_json() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let mut dir = ::std::env::temp_dir();
+        dir.push("wrench-json-frame");
+        fs::remove_dir_all(&dir).ok();
+        let mut writer = JsonFrameWriter::new(&dir);
+        unsafe {
+            CURRENT_FRAME_NUMBER = 1;
+        }
+
+        let mut builder = DisplayListBuilder::new(PipelineId::dummy(), LayoutSize::new(100.0, 100.0));
+        let info = LayoutPrimitiveInfo::new(LayoutRect::new(
+            LayoutPoint::zero(),
+            LayoutSize::new(16.0, 16.0),
+        ));
+        builder.push_rect(&info, ColorF::new(1.0, 0.0, 0.0, 1.0));
+        let (data, descriptor) = builder.finalize().2.into_data();
+
+        writer.begin_write_display_list(
+            &Epoch(0),
+            &PipelineId::dummy(),
+            &None,
+            &LayoutSize::new(100.0, 100.0),
+            &LayoutSize::new(100.0, 100.0),
+            &descriptor,
+        );
+        let payload = Payload {
+            epoch: Epoch(0),
+            pipeline_id: PipelineId::dummy(),
+            display_list_data: data,
+        };
+        writer.finish_write_display_list(1, &payload.to_data());
+
+        let contents = fs::read_to_string(dir.join("frame-1.json")).unwrap();
+        let frame: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        for key in &["format_version", "header", "images", "display_list"] {
+            assert!(frame.get(key).is_some(), "missing {}", key);
+        }
+        assert_eq!(frame["format_version"].as_u64(), Some(FRAME_FORMAT_VERSION as u64));
+        assert_eq!(frame["header"]["item_count"].as_u64(), Some(1));
+    }
+
+    #[test]
+    fn chunked_payload_is_assembled() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let mut dir = ::std::env::temp_dir();
+        dir.push("wrench-json-chunked-payload");
+        fs::remove_dir_all(&dir).ok();
+        let mut writer = JsonFrameWriter::new(&dir);
+        unsafe {
+            CURRENT_FRAME_NUMBER = 1;
+        }
+
+        let mut builder = DisplayListBuilder::new(PipelineId::dummy(), LayoutSize::new(100.0, 100.0));
+        for i in 0 .. 2 {
+            let info = LayoutPrimitiveInfo::new(LayoutRect::new(
+                LayoutPoint::new(i as f32 * 20.0, 0.0),
+                LayoutSize::new(16.0, 16.0),
+            ));
+            builder.push_rect(&info, ColorF::new(1.0, 0.0, 0.0, 1.0));
+        }
+        let (data, descriptor) = builder.finalize().2.into_data();
+
+        writer.begin_write_display_list(
+            &Epoch(0),
+            &PipelineId::dummy(),
+            &None,
+            &LayoutSize::new(100.0, 100.0),
+            &LayoutSize::new(100.0, 100.0),
+            &descriptor,
+        );
+        let payload = Payload {
+            epoch: Epoch(0),
+            pipeline_id: PipelineId::dummy(),
+            display_list_data: data,
+        }.to_data();
+        let (first, second) = payload.split_at(payload.len() / 2);
+
+        writer.write_payload(1, first);
+        assert!(!dir.join("frame-1.json").exists());
+        writer.write_payload(1, second);
+
+        let contents = fs::read_to_string(dir.join("frame-1.json")).unwrap();
+        let frame: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        assert_eq!(frame["header"]["item_count"].as_u64(), Some(2));
+        assert_eq!(frame["display_list"].as_array().map(|items| items.len()), Some(2));
+    }
+}
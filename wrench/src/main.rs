@@ -33,6 +33,7 @@ extern crate yaml_rust;
 
 mod binary_frame_reader;
 mod blob;
+mod frame_writer;
 mod json_frame_writer;
 mod parse_function;
 mod perf;
@@ -54,6 +55,7 @@ use perf::PerfHarness;
 use png::save_flipped;
 use rawtest::RawtestHarness;
 use reftest::{ReftestHarness, ReftestOptions};
+use ron_frame_writer::RonOptions;
 use std::cmp::{max, min};
 #[cfg(feature = "headless")]
 use std::ffi::CString;
@@ -77,6 +79,12 @@ lazy_static! {
 
 pub static mut CURRENT_FRAME_NUMBER: u32 = 0;
 
+#[cfg(test)]
+lazy_static! {
+    // CURRENT_FRAME_NUMBER is a global, so tests that write frames must not overlap.
+    static ref FRAME_LOCK: ::std::sync::Mutex<()> = ::std::sync::Mutex::new(());
+}
+
 fn percentile(values: &[f64], pct_int: u32) -> f64 {
     if !values.is_empty() {
         let index_big = (values.len() - 1) * (pct_int as usize);
@@ -91,6 +99,25 @@ fn percentile(values: &[f64], pct_int: u32) -> f64 {
     }
 }
 
+// Parse the `--save-frames` range, which is either FIRST-LAST or a single frame.
+fn parse_frame_range(range: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("Invalid frame range '{}', expected FIRST-LAST or a single frame", range);
+    let mut numbers = range.splitn(2, '-').map(|n| n.parse::<u32>());
+    let first = match numbers.next() {
+        Some(Ok(first)) => first,
+        _ => return Err(invalid()),
+    };
+    let last = match numbers.next() {
+        Some(Ok(last)) => last,
+        Some(Err(_)) => return Err(invalid()),
+        None => first,
+    };
+    if last < first {
+        return Err(invalid());
+    }
+    Ok((first, last))
+}
+
 #[cfg(feature = "headless")]
 pub struct HeadlessContext {
     width: u32,
@@ -325,7 +352,18 @@ fn main() {
     let save_type = args.value_of("save").map(|s| match s {
         "yaml" => wrench::SaveType::Yaml,
         "json" => wrench::SaveType::Json,
-        "ron" => wrench::SaveType::Ron,
+        "ron" => wrench::SaveType::Ron(RonOptions {
+            append: args.is_present("save_append"),
+            thumbnails: args.is_present("save_thumbnails"),
+            trees: args.is_present("save_trees"),
+            stream_resources: args.is_present("save_stream_resources"),
+            normalize_epochs: args.is_present("save_normalize_epochs"),
+            frames: args.value_of("save_frames").map(|s| {
+                parse_frame_range(s).unwrap_or_else(|message| {
+                    clap::Error::with_description(&message, clap::ErrorKind::InvalidValue).exit()
+                })
+            }),
+        }),
         "binary" => wrench::SaveType::Binary,
         _ => panic!("Save type must be json, ron, yaml, or binary")
     });
@@ -2,38 +2,163 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use frame_writer::{CachedImage, DocumentView, Frame, FrameResources, WriterError};
+use frame_writer::print_error;
+use image::{self, ColorType, FilterType};
+use image::png::PNGEncoder;
+use premultiply::unpremultiply;
 use ron;
-use std::borrow::BorrowMut;
 use std::collections::HashMap;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::{fmt, fs};
+use std::{fmt, fs, mem};
 use super::CURRENT_FRAME_NUMBER;
 use webrender;
 use webrender::api::*;
 use webrender::api::channel::Payload;
 
-enum CachedFont {
-    Native(NativeFontHandle),
-    Raw(Option<Vec<u8>>, u32, Option<PathBuf>),
+// Largest width or height of the frame thumbnails.
+const THUMBNAIL_SIZE: u32 = 64;
+
+// The highest N of the `frame-N.ron` files in `path`, if there are any.
+fn last_frame_number(path: &Path) -> Option<u32> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return None,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => return None,
+            };
+            if !name.starts_with("frame-") || !name.ends_with(".ron") {
+                return None;
+            }
+            name["frame-".len() .. name.len() - ".ron".len()].parse().ok()
+        })
+        .max()
+}
+
+/// The optional parts of a capture, see the setters of `RonFrameWriter`.
+#[derive(Clone, Debug, Default)]
+pub struct RonOptions {
+    /// Continue the capture found at the destination, see `open_existing`.
+    pub append: bool,
+    pub thumbnails: bool,
+    pub trees: bool,
+    pub stream_resources: bool,
+    pub normalize_epochs: bool,
+    /// The first and last frame to write.
+    pub frames: Option<(u32, u32)>,
+}
+
+/// The nesting of the stacking contexts of a frame, which is otherwise only
+/// implied by the push and pop items of the display list.
+#[derive(Debug, Serialize)]
+pub struct StackingContextNode {
+    pub rect: LayoutRect,
+    /// The number of items directly in this stacking context, leaving out
+    /// the pushes and pops of its children.
+    pub item_count: usize,
+    pub children: Vec<StackingContextNode>,
+}
+
+impl StackingContextNode {
+    fn new(rect: LayoutRect) -> Self {
+        StackingContextNode {
+            rect,
+            item_count: 0,
+            children: Vec::new(),
+        }
+    }
+
+    /// The tree of a display list. The root stands for the frame itself,
+    /// covering the viewport.
+    pub fn from_display_list(dl: &BuiltDisplayList, viewport_size: LayoutSize) -> Self {
+        let root = LayoutRect::new(LayoutPoint::zero(), viewport_size);
+        let mut stack = vec![StackingContextNode::new(root)];
+        let mut traversal = dl.iter();
+        while let Some(item) = traversal.next() {
+            match *item.item() {
+                SpecificDisplayItem::PushStackingContext(..) => {
+                    stack.push(StackingContextNode::new(item.rect()));
+                }
+                SpecificDisplayItem::PopStackingContext if stack.len() > 1 => {
+                    let node = stack.pop().unwrap();
+                    stack.last_mut().unwrap().children.push(node);
+                }
+                _ => stack.last_mut().unwrap().item_count += 1,
+            }
+        }
+
+        // Close the stacking contexts that are never popped.
+        while stack.len() > 1 {
+            let node = stack.pop().unwrap();
+            stack.last_mut().unwrap().children.push(node);
+        }
+        stack.pop().unwrap()
+    }
+
+    /// The number of nested stacking context levels below this one.
+    pub fn depth(&self) -> usize {
+        self.children.iter().map(|child| child.depth() + 1).max().unwrap_or(0)
+    }
+}
+
+/// Facts about the capture as a whole, written to `manifest.ron`.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CaptureManifest {
+    /// The first frame that draws anything.
+    pub first_paint: Option<u32>,
+    /// The images known as of the last written frame, in key order, so that
+    /// a resumed capture can keep referring to them.
+    pub images: Vec<(ImageKey, ImageDescriptor)>,
+}
+
+/// Opens the destination of a file of the capture, given the path the file
+/// would be written to.
+pub type OutputFactory = Box<FnMut(&Path) -> io::Result<Box<Write>>>;
+
+fn create_file(path: &Path) -> io::Result<Box<Write>> {
+    fs::File::create(path).map(|file| Box::new(file) as Box<Write>)
 }
 
-struct CachedImage {
-    width: u32,
-    height: u32,
-    format: ImageFormat,
-    bytes: Option<Vec<u8>>,
-    path: Option<PathBuf>,
+fn write_to(output: &mut OutputFactory, path: &Path, chunks: &[&[u8]]) -> io::Result<()> {
+    let mut output = (*output)(path)?;
+    for chunk in chunks {
+        output.write_all(chunk)?;
+    }
+    Ok(())
 }
 
 pub struct RonFrameWriter {
     frame_base: PathBuf,
-    images: HashMap<ImageKey, CachedImage>,
-    fonts: HashMap<FontKey, CachedFont>,
+    resources: FrameResources,
 
     last_frame_written: u32,
+    // The number of the next frame file, when continuing an existing capture.
+    next_frame_number: Option<u32>,
 
     dl_descriptor: Option<BuiltDisplayListDescriptor>,
+    pending_payload: Vec<u8>,
+    background: Option<ColorF>,
+    viewport_size: LayoutSize,
+    content_size: LayoutSize,
+    view: DocumentView,
+
+    active: bool,
+    // The first and last frame to write, if not all of them.
+    frame_range: Option<(u32, u32)>,
+    write_thumbnails: bool,
+    stream_resources: bool,
+    write_trees: bool,
+    // The last epoch written for each pipeline, if epochs are normalized.
+    normalized_epochs: Option<HashMap<PipelineId, Epoch>>,
+    error_sink: Box<FnMut(WriterError)>,
+    output: OutputFactory,
+    manifest: CaptureManifest,
 }
 
 impl RonFrameWriter {
@@ -44,12 +169,146 @@ impl RonFrameWriter {
 
         RonFrameWriter {
             frame_base: path.to_owned(),
-            images: HashMap::new(),
-            fonts: HashMap::new(),
+            resources: FrameResources::new(),
 
             dl_descriptor: None,
+            pending_payload: Vec::new(),
+            background: None,
+            viewport_size: LayoutSize::zero(),
+            content_size: LayoutSize::zero(),
+            view: DocumentView::new(),
 
             last_frame_written: u32::max_value(),
+            next_frame_number: None,
+
+            active: true,
+            frame_range: None,
+            write_thumbnails: false,
+            stream_resources: false,
+            write_trees: false,
+            normalized_epochs: None,
+            error_sink: Box::new(print_error),
+            output: Box::new(create_file),
+            manifest: CaptureManifest::default(),
+        }
+    }
+
+    /// A writer with the optional outputs of `options` enabled.
+    pub fn with_options(path: &Path, options: &RonOptions) -> Self {
+        let mut writer = match options.append {
+            true => Self::open_existing(path),
+            false => Self::new(path),
+        };
+        writer.set_write_thumbnails(options.thumbnails);
+        writer.set_write_trees(options.trees);
+        writer.set_stream_resources(options.stream_resources);
+        writer.set_normalize_epochs(options.normalize_epochs);
+        if let Some((first, last)) = options.frames {
+            writer.set_frame_range(first, last);
+        }
+        writer
+    }
+
+    /// Continue a capture previously written to `path`, numbering the new
+    /// frames after the last one found there. The images listed in its
+    /// manifest are known again, so that the new frames and updates can
+    /// refer to them. Only the bytes of streamed images are available.
+    pub fn open_existing(path: &Path) -> Self {
+        let mut writer = Self::new(path);
+        writer.next_frame_number = last_frame_number(path).map(|number| number + 1);
+        writer.manifest = fs::read_to_string(path.join("manifest.ron"))
+            .ok()
+            .and_then(|manifest| ron::de::from_str(&manifest).ok())
+            .unwrap_or_default();
+        for &(key, descriptor) in &writer.manifest.images {
+            let image_path = writer.image_path(key);
+            let image = CachedImage {
+                descriptor,
+                bytes: None,
+                path: if image_path.exists() { Some(image_path) } else { None },
+                external: None,
+                blob: None,
+            };
+            writer.resources.images.insert(key, image);
+        }
+        writer
+    }
+
+    /// Also write a small `frame-N.png` preview next to each frame, for
+    /// quickly browsing through a capture. Since nothing is rendered, this
+    /// is only the first opaque image of the frame, or its background color.
+    pub fn set_write_thumbnails(&mut self, enabled: bool) {
+        self.write_thumbnails = enabled;
+    }
+
+    /// Also write the nesting of the stacking contexts of each frame to
+    /// `frame-N.tree.ron`, see `StackingContextNode`.
+    pub fn set_write_trees(&mut self, enabled: bool) {
+        self.write_trees = enabled;
+    }
+
+    /// Write epochs that increase with every frame of a pipeline, since the
+    /// compositor only accepts newer frames when the capture is replayed.
+    /// Epochs that already do are written as they are.
+    pub fn set_normalize_epochs(&mut self, enabled: bool) {
+        self.normalized_epochs = match enabled {
+            true => Some(HashMap::new()),
+            false => None,
+        };
+    }
+
+    fn recorded_epoch(&mut self, pipeline_id: PipelineId, epoch: Epoch) -> Epoch {
+        let last_epochs = match self.normalized_epochs {
+            Some(ref mut last_epochs) => last_epochs,
+            None => return epoch,
+        };
+        let epoch = match last_epochs.get(&pipeline_id) {
+            Some(last) if epoch <= *last => Epoch(last.0 + 1),
+            _ => epoch,
+        };
+        last_epochs.insert(pipeline_id, epoch);
+        epoch
+    }
+
+    /// Write the bytes of images to the `res` directory as soon as they are
+    /// added or updated, instead of keeping them in memory. This bounds the
+    /// memory used by long captures.
+    pub fn set_stream_resources(&mut self, enabled: bool) {
+        self.stream_resources = enabled;
+    }
+
+    /// Only write the frames from `first` to `last`, inclusive, e.g. to
+    /// capture a part of a long session.
+    pub fn set_frame_range(&mut self, first: u32, last: u32) {
+        self.frame_range = Some((first, last));
+    }
+
+    /// Report the problems the writer works around to `sink`, instead of
+    /// printing them.
+    pub fn set_error_sink(&mut self, sink: Box<FnMut(WriterError)>) {
+        self.error_sink = sink;
+    }
+
+    /// Write the files of the capture, including streamed images and
+    /// thumbnails, to the outputs that `output` opens instead of to disk.
+    /// Thumbnails are made from images that are still in memory then.
+    pub fn set_output(&mut self, output: OutputFactory) {
+        self.output = output;
+    }
+
+    /// Start or stop writing frames. While inactive no frames are written,
+    /// but resource updates are still tracked so that frames written after
+    /// the capture is resumed refer to valid resources. With a frame range,
+    /// this is set for each message according to the range.
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+
+    // The number of the file the next frame is written to.
+    fn frame_number(&self) -> u32 {
+        match self.next_frame_number {
+            Some(number) => number,
+            None => unsafe { CURRENT_FRAME_NUMBER },
         }
     }
 
@@ -57,8 +316,9 @@ impl RonFrameWriter {
         &mut self,
         _: &Epoch,
         _: &PipelineId,
-        _: &Option<ColorF>,
-        _: &LayoutSize,
+        background: &Option<ColorF>,
+        viewport_size: &LayoutSize,
+        content_size: &LayoutSize,
         display_list: &BuiltDisplayListDescriptor,
     ) {
         unsafe {
@@ -69,79 +329,206 @@ impl RonFrameWriter {
         }
 
         self.dl_descriptor = Some(display_list.clone());
+        self.pending_payload.clear();
+        self.background = *background;
+        self.viewport_size = *viewport_size;
+        self.content_size = *content_size;
     }
 
     pub fn finish_write_display_list(&mut self, _frame: u32, data: &[u8]) {
         let payload = Payload::from_data(data);
+        let pipeline_id = payload.pipeline_id;
+        let epoch = self.recorded_epoch(pipeline_id, payload.epoch);
         let dl_desc = self.dl_descriptor.take().unwrap();
 
         let dl = BuiltDisplayList::from_data(payload.display_list_data, dl_desc);
 
         let mut frame_file_name = self.frame_base.clone();
-        let current_shown_frame = unsafe { CURRENT_FRAME_NUMBER };
+        let current_shown_frame = self.frame_number();
+        if let Some(ref mut number) = self.next_frame_number {
+            *number += 1;
+        }
         frame_file_name.push(format!("frame-{}.ron", current_shown_frame));
 
-        let mut file = fs::File::create(&frame_file_name).unwrap();
+        let (s, is_first_paint, images_changed) = {
+            let frame = Frame::new(
+                &dl,
+                &self.resources,
+                pipeline_id,
+                epoch,
+                self.viewport_size,
+                self.content_size,
+                self.view,
+            );
+            let is_first_paint =
+                self.manifest.first_paint.is_none() && frame.header.drawing_item_count > 0;
+            let images_changed = frame.images != self.manifest.images;
+            match ron::ser::pretty::to_string(&frame) {
+                Ok(s) => (s, is_first_paint, images_changed),
+                Err(err) => {
+                    (self.error_sink)(WriterError::SerializeFailed(err.to_string()));
+                    return;
+                }
+            }
+        };
 
-        let s = ron::ser::pretty::to_string(&dl).unwrap();
-        file.write_all(&s.into_bytes()).unwrap();
-        file.write_all(b"\n").unwrap();
-    }
+        self.write_output(&frame_file_name, &[s.as_bytes(), b"\n"]);
 
-    fn update_resources(&mut self, updates: &ResourceUpdates) {
-        for update in &updates.updates {
-            match *update {
-                ResourceUpdate::AddImage(ref img) => {
-                    let bytes = match img.data {
-                        ImageData::Raw(ref v) => (**v).clone(),
-                        ImageData::External(_) | ImageData::Blob(_) => {
-                            return;
-                        }
-                    };
-                    self.images.insert(
-                        img.key,
-                        CachedImage {
-                            width: img.descriptor.width,
-                            height: img.descriptor.height,
-                            format: img.descriptor.format,
-                            bytes: Some(bytes),
-                            path: None,
-                        },
-                    );
-                }
-                ResourceUpdate::UpdateImage(ref img) => {
-                    if let Some(ref mut data) = self.images.get_mut(&img.key) {
-                        assert_eq!(data.width, img.descriptor.width);
-                        assert_eq!(data.height, img.descriptor.height);
-                        assert_eq!(data.format, img.descriptor.format);
-
-                        if let ImageData::Raw(ref bytes) = img.data {
-                            *data.path.borrow_mut() = None;
-                            *data.bytes.borrow_mut() = Some((**bytes).clone());
-                        } else {
-                            // Other existing image types only make sense within the gecko integration.
-                            println!(
-                                "Wrench only supports updating buffer images ({}).",
-                                "ignoring update commands"
-                            );
-                        }
-                    }
+        if is_first_paint || images_changed {
+            if is_first_paint {
+                self.manifest.first_paint = Some(current_shown_frame);
+            }
+            self.manifest.images = self.resources.image_descriptors();
+            self.write_manifest();
+        }
+
+        if self.write_trees {
+            let tree = StackingContextNode::from_display_list(&dl, self.viewport_size);
+            match ron::ser::pretty::to_string(&tree) {
+                Ok(tree) => {
+                    let name = format!("frame-{}.tree.ron", current_shown_frame);
+                    let path = self.frame_base.join(name);
+                    self.write_output(&path, &[tree.as_bytes(), b"\n"]);
                 }
-                ResourceUpdate::DeleteImage(img) => {
-                    self.images.remove(&img);
+                Err(err) => (self.error_sink)(WriterError::SerializeFailed(err.to_string())),
+            }
+        }
+
+        if self.write_thumbnails {
+            let path = self.frame_base.join(format!("frame-{}.png", current_shown_frame));
+            if let Some(thumbnail) = self.thumbnail(&dl) {
+                let mut png = Vec::new();
+                let (width, height) = thumbnail.dimensions();
+                match PNGEncoder::new(&mut png).encode(&thumbnail, width, height, ColorType::RGBA(8)) {
+                    Ok(()) => self.write_output(&path, &[&png[..]]),
+                    Err(err) => (self.error_sink)(WriterError::WriteFailed {
+                        path,
+                        message: err.to_string(),
+                    }),
                 }
-                ResourceUpdate::AddFont(ref font) => match font {
-                    &AddFont::Raw(key, ref bytes, index) => {
-                        self.fonts
-                            .insert(key, CachedFont::Raw(Some(bytes.clone()), index, None));
-                    }
-                    &AddFont::Native(key, ref handle) => {
-                        self.fonts.insert(key, CachedFont::Native(handle.clone()));
-                    }
+            }
+        }
+    }
+
+    fn write_manifest(&mut self) {
+        let manifest = match ron::ser::pretty::to_string(&self.manifest) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                (self.error_sink)(WriterError::SerializeFailed(err.to_string()));
+                return;
+            }
+        };
+        let path = self.frame_base.join("manifest.ron");
+        self.write_output(&path, &[manifest.as_bytes(), b"\n"]);
+    }
+
+    fn write_output(&mut self, path: &Path, chunks: &[&[u8]]) {
+        if let Err(err) = write_to(&mut self.output, path, chunks) {
+            (self.error_sink)(WriterError::WriteFailed {
+                path: path.to_owned(),
+                message: err.to_string(),
+            });
+        }
+    }
+
+    // The preview of a frame, see `set_write_thumbnails`.
+    fn thumbnail(&self, dl: &BuiltDisplayList) -> Option<image::RgbaImage> {
+        let mut traversal = dl.iter();
+        while let Some(item) = traversal.next() {
+            let key = match *item.item() {
+                SpecificDisplayItem::Image(ref info) => info.image_key,
+                _ => continue,
+            };
+            let image = match self.resources.images.get(&key) {
+                Some(image) => image,
+                None => continue,
+            };
+            let desc = &image.descriptor;
+            let tightly_packed = desc.stride.map_or(true, |stride| stride == desc.width * 4);
+            if desc.format != ImageFormat::BGRA8 || !desc.is_opaque || !tightly_packed {
+                continue;
+            }
+            let mut bytes = match (&image.bytes, &image.path) {
+                (&Some(ref bytes), _) => bytes[desc.offset as usize ..].to_vec(),
+                (&None, &Some(ref path)) => match fs::read(path) {
+                    Ok(bytes) => bytes[desc.offset as usize ..].to_vec(),
+                    Err(_) => continue,
                 },
-                ResourceUpdate::DeleteFont(_) => {}
-                ResourceUpdate::AddFontInstance(_) => {}
-                ResourceUpdate::DeleteFontInstance(_) => {}
+                (&None, &None) => continue,
+            };
+            bytes.truncate((desc.width * desc.height * 4) as usize);
+            unpremultiply(bytes.as_mut_slice());
+
+            let buffer = match image::RgbaImage::from_raw(desc.width, desc.height, bytes) {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            let scale = (THUMBNAIL_SIZE as f32 / desc.width.max(desc.height) as f32).min(1.0);
+            return Some(image::imageops::resize(
+                &buffer,
+                ((desc.width as f32 * scale) as u32).max(1),
+                ((desc.height as f32 * scale) as u32).max(1),
+                FilterType::Triangle,
+            ));
+        }
+
+        // No image to show, fall back to a swatch of the background color.
+        self.background.map(|color| {
+            let pixel = image::Rgba([
+                (color.r * 255.0) as u8,
+                (color.g * 255.0) as u8,
+                (color.b * 255.0) as u8,
+                (color.a * 255.0) as u8,
+            ]);
+            let size = THUMBNAIL_SIZE / 4;
+            image::RgbaImage::from_pixel(size, size, pixel)
+        })
+    }
+
+    fn update_resources(&mut self, updates: &ResourceUpdates) {
+        self.resources.update(updates, &mut *self.error_sink);
+
+        if self.stream_resources {
+            for update in &updates.updates {
+                match *update {
+                    ResourceUpdate::AddImage(ref img) => self.stream_image(img.key),
+                    ResourceUpdate::UpdateImage(ref img) => self.stream_image(img.key),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn image_path(&self, key: ImageKey) -> PathBuf {
+        let mut path = self.frame_base.clone();
+        path.push("res");
+        path.push(format!("image-{}-{}.bin", (key.0).0, key.1));
+        path
+    }
+
+    // Move the bytes of an image to its file in the `res` directory. An
+    // update of the image overwrites the file.
+    fn stream_image(&mut self, key: ImageKey) {
+        let bytes = match self.resources.images.get_mut(&key) {
+            Some(image) => image.bytes.take(),
+            None => return,
+        };
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => return,
+        };
+
+        let path = self.image_path(key);
+        let result = write_to(&mut self.output, &path, &[&bytes[..]]);
+        let image = self.resources.images.get_mut(&key).unwrap();
+        match result {
+            Ok(()) => image.path = Some(path),
+            Err(err) => {
+                (self.error_sink)(WriterError::ImageWriteFailed {
+                    key,
+                    message: err.to_string(),
+                });
+                image.bytes = Some(bytes);
             }
         }
     }
@@ -155,6 +542,13 @@ impl fmt::Debug for RonFrameWriter {
 
 impl webrender::ApiRecordingReceiver for RonFrameWriter {
     fn write_msg(&mut self, _: u32, msg: &ApiMsg) {
+        // The range refers to the numbers of the written files, which
+        // differ from the recorder's frame count when appending.
+        if let Some((first, last)) = self.frame_range {
+            let frame = self.frame_number();
+            self.set_active(frame >= first && frame <= last);
+        }
+
         match *msg {
             ApiMsg::UpdateResources(ref updates) => self.update_resources(updates),
 
@@ -165,28 +559,511 @@ impl webrender::ApiRecordingReceiver for RonFrameWriter {
                     ref pipeline_id,
                     ref background,
                     ref viewport_size,
+                    ref content_size,
                     ref list_descriptor,
                     ref resources,
                     ..
                 },
             ) => {
                 self.update_resources(resources);
+                if !self.active {
+                    return;
+                }
                 self.begin_write_display_list(
                     epoch,
                     pipeline_id,
                     background,
                     viewport_size,
+                    content_size,
                     list_descriptor,
                 );
             }
+            ApiMsg::UpdateDocument(_, ref msg) => self.view.update(msg),
             ApiMsg::CloneApi(..) => {}
             _ => {}
         }
     }
 
     fn write_payload(&mut self, frame: u32, data: &[u8]) {
-        if self.dl_descriptor.is_some() {
-            self.finish_write_display_list(frame, data);
+        if !self.active || self.dl_descriptor.is_none() {
+            return;
+        }
+
+        // Large payloads may arrive in several chunks, so hold on to
+        // them until the whole display list is there.
+        self.pending_payload.extend_from_slice(data);
+        if Payload::is_complete(&self.pending_payload) {
+            let payload = mem::replace(&mut self.pending_payload, Vec::new());
+            self.finish_write_display_list(frame, &payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImage;
+    use frame_writer::{FRAME_FORMAT_VERSION, FrameHeader};
+    use FRAME_LOCK;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use webrender::ApiRecordingReceiver;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let mut path = ::std::env::temp_dir();
+        path.push(format!("wrench-ron-{}", name));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn build_display_list<F: FnOnce(&mut DisplayListBuilder)>(f: F) -> BuiltDisplayList {
+        let mut builder = DisplayListBuilder::new(PipelineId::dummy(), LayoutSize::new(100.0, 100.0));
+        f(&mut builder);
+        builder.finalize().2
+    }
+
+    fn empty_display_list() -> BuiltDisplayList {
+        build_display_list(|_| {})
+    }
+
+    fn info(size: f32) -> LayoutPrimitiveInfo {
+        LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(size, size)))
+    }
+
+    fn push_image(builder: &mut DisplayListBuilder, size: f32, rendering: ImageRendering, key: ImageKey) {
+        builder.push_image(&info(size), LayoutSize::new(size, size), LayoutSize::zero(), rendering, key);
+    }
+
+    fn image_key(key: u32) -> ImageKey {
+        ImageKey::new(IdNamespace(0), key)
+    }
+
+    fn descriptor(size: u32) -> ImageDescriptor {
+        ImageDescriptor::new(size, size, ImageFormat::BGRA8, true)
+    }
+
+    // The recorder messages that set `dl` as the display list of `frame`,
+    // which is the message and the payload.
+    fn display_list_msg(
+        frame: u32,
+        epoch: Epoch,
+        dl: BuiltDisplayList,
+        resources: ResourceUpdates,
+    ) -> (ApiMsg, Vec<u8>) {
+        unsafe {
+            CURRENT_FRAME_NUMBER = frame;
         }
+        let (data, descriptor) = dl.into_data();
+        let msg = ApiMsg::UpdateDocument(
+            DocumentId(IdNamespace(0), 0),
+            DocumentMsg::SetDisplayList {
+                list_descriptor: descriptor,
+                epoch,
+                pipeline_id: PipelineId::dummy(),
+                background: None,
+                viewport_size: LayoutSize::new(100.0, 100.0),
+                content_size: LayoutSize::new(100.0, 2345.5),
+                preserve_frame_state: false,
+                resources,
+            },
+        );
+        let payload = Payload {
+            epoch,
+            pipeline_id: PipelineId::dummy(),
+            display_list_data: data,
+        };
+        (msg, payload.to_data())
+    }
+
+    fn record_frame(
+        writer: &mut RonFrameWriter,
+        frame: u32,
+        dl: BuiltDisplayList,
+        resources: ResourceUpdates,
+    ) {
+        let (msg, payload) = display_list_msg(frame, Epoch(0), dl, resources);
+        writer.write_msg(frame, &msg);
+        writer.write_payload(frame, &payload);
+    }
+
+    fn record_resources(writer: &mut RonFrameWriter, resources: ResourceUpdates) {
+        writer.write_msg(0, &ApiMsg::UpdateResources(resources));
+    }
+
+    fn collect_errors(writer: &mut RonFrameWriter) -> Rc<RefCell<Vec<WriterError>>> {
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let sink_errors = Rc::clone(&errors);
+        writer.set_error_sink(Box::new(move |error| sink_errors.borrow_mut().push(error)));
+        errors
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Make `writer` write to buffers, which are returned along with the
+    // paths they stand for.
+    fn capture_outputs(writer: &mut RonFrameWriter) -> Rc<RefCell<Vec<(PathBuf, SharedBuffer)>>> {
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let factory_outputs = outputs.clone();
+        writer.set_output(Box::new(move |path: &Path| {
+            let buffer = SharedBuffer::default();
+            factory_outputs.borrow_mut().push((path.to_owned(), buffer.clone()));
+            Ok(Box::new(buffer) as Box<Write>)
+        }));
+        outputs
+    }
+
+    #[derive(Deserialize)]
+    struct WrittenView {
+        page_zoom: f32,
+        pinch_zoom: f32,
+        pan: DeviceIntPoint,
+    }
+
+    // `Frame`, as read back from a frame without external images, blob
+    // images or items.
+    #[derive(Deserialize)]
+    struct WrittenFrame {
+        format_version: u32,
+        header: FrameHeader,
+        pipeline_id: PipelineId,
+        epoch: Epoch,
+        viewport_size: LayoutSize,
+        content_size: LayoutSize,
+        view: WrittenView,
+        images: Vec<(ImageKey, ImageDescriptor)>,
+        external_images: Vec<ImageKey>,
+        blob_images: Vec<ImageKey>,
+        display_list: Vec<u8>,
+    }
+
+    fn read_frame(path: &Path) -> WrittenFrame {
+        ron::de::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn frames_are_written_to_the_output_factory() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("output-factory");
+        let mut writer = RonFrameWriter::new(&dir);
+        let outputs = capture_outputs(&mut writer);
+
+        // Large payloads may arrive in several chunks.
+        let (msg, payload) = display_list_msg(1, Epoch(0), empty_display_list(), ResourceUpdates::new());
+        let (first, second) = payload.split_at(payload.len() / 2);
+        writer.write_msg(1, &msg);
+        writer.write_payload(1, first);
+        assert!(outputs.borrow().is_empty());
+        writer.write_payload(1, second);
+
+        let path = dir.join("frame-1.ron");
+        assert!(!path.exists());
+        let outputs = outputs.borrow();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].0, path);
+        let contents = String::from_utf8(outputs[0].1 .0.borrow().clone()).unwrap();
+        let frame: WrittenFrame = ron::de::from_str(&contents).unwrap();
+        assert_eq!(frame.format_version, FRAME_FORMAT_VERSION);
+        assert_eq!(frame.header.item_count, 0);
+        assert_eq!((frame.pipeline_id, frame.epoch), (PipelineId::dummy(), Epoch(0)));
+        assert_eq!(frame.viewport_size, LayoutSize::new(100.0, 100.0));
+        assert_eq!(frame.content_size, LayoutSize::new(100.0, 2345.5));
+        assert_eq!((frame.view.page_zoom, frame.view.pinch_zoom), (1.0, 1.0));
+        assert_eq!(frame.view.pan, DeviceIntPoint::zero());
+        assert!(frame.images.is_empty() && frame.external_images.is_empty());
+        assert!(frame.blob_images.is_empty());
+        assert!(frame.display_list.is_empty());
+    }
+
+    #[test]
+    fn display_list_is_written() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("display-list");
+        let mut writer = RonFrameWriter::new(&dir);
+
+        let msg = ApiMsg::UpdateDocument(
+            DocumentId(IdNamespace(0), 0),
+            DocumentMsg::SetPinchZoom(ZoomFactor::new(2.5)),
+        );
+        writer.write_msg(1, &msg);
+        let dl = build_display_list(|builder| {
+            let mut tagged = info(16.0);
+            tagged.tag = Some((4242, 31337));
+            builder.push_rect(&tagged, ColorF::new(1.0, 0.0, 0.0, 1.0));
+            push_image(builder, 16.0, ImageRendering::Pixelated, image_key(1));
+        });
+        record_frame(&mut writer, 1, dl, ResourceUpdates::new());
+
+        let contents = fs::read_to_string(dir.join("frame-1.ron")).unwrap();
+        for expected in &["item_count: 2", "tag: Some(", "31337", "Pixelated", "pinch_zoom: 2.5"] {
+            assert!(contents.contains(*expected), "{} is missing", expected);
+        }
+    }
+
+    #[test]
+    fn images_are_listed_with_the_frame() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("images");
+        let mut writer = RonFrameWriter::new(&dir);
+        let errors = collect_errors(&mut writer);
+
+        let strided = ImageDescriptor {
+            stride: Some(12),
+            offset: 4,
+            ..ImageDescriptor::new(2, 2, ImageFormat::BGRA8, false)
+        };
+        let external = ExternalImageData {
+            id: ExternalImageId(7),
+            channel_index: 1,
+            image_type: ExternalImageType::Texture2DHandle,
+        };
+        let mut updates = ResourceUpdates::new();
+        updates.add_image(image_key(1), strided, ImageData::new(vec![0; 28]), None);
+        updates.add_image(image_key(2), descriptor(2), ImageData::External(external), None);
+        updates.add_image(image_key(3), descriptor(256), ImageData::new_blob_image(vec![1]), Some(64));
+        record_resources(&mut writer, updates);
+        let mut updates = ResourceUpdates::new();
+        let dirty_rect = DeviceUintRect::new(DeviceUintPoint::new(70, 10), DeviceUintSize::new(60, 20));
+        let blob = ImageData::new_blob_image(vec![2]);
+        updates.update_image(image_key(3), descriptor(256), blob, Some(dirty_rect));
+        record_resources(&mut writer, updates);
+
+        // Only the tiling of blob images is recorded.
+        assert_eq!(
+            *errors.borrow(),
+            vec![WriterError::SkippedBlobImage(image_key(3)), WriterError::SkippedBlobImage(image_key(3))]
+        );
+
+        record_frame(&mut writer, 1, empty_display_list(), ResourceUpdates::new());
+        let contents = fs::read_to_string(dir.join("frame-1.ron")).unwrap();
+        for expected in &[
+            "stride: Some(12)",
+            "offset: 4",
+            "is_opaque: false",
+            "channel_index: 1",
+            "tile_size: Some(64)",
+            "dirty_tiles: Some(",
+        ] {
+            assert!(contents.contains(*expected), "{} is missing", expected);
+        }
+    }
+
+    #[test]
+    fn resource_update_problems_are_reported() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("update-problems");
+        let mut writer = RonFrameWriter::new(&dir);
+        let errors = collect_errors(&mut writer);
+        let (key, unknown_key, deleted_key) = (image_key(1), image_key(2), image_key(3));
+
+        let mut updates = ResourceUpdates::new();
+        updates.add_image(key, descriptor(2), ImageData::new(vec![0; 16]), None);
+        updates.update_image(key, descriptor(4), ImageData::new(vec![0; 64]), None);
+        updates.update_image(unknown_key, descriptor(2), ImageData::new(vec![0; 16]), None);
+        updates.add_image(deleted_key, descriptor(1), ImageData::new(vec![0; 4]), None);
+        updates.update_image(deleted_key, descriptor(1), ImageData::new(vec![1; 4]), None);
+        updates.delete_image(deleted_key);
+        record_resources(&mut writer, updates);
+
+        assert_eq!(
+            *errors.borrow(),
+            vec![
+                WriterError::UpdateSizeMismatch { key, old: descriptor(2), new: descriptor(4) },
+                WriterError::UnknownImageUpdate(unknown_key),
+            ]
+        );
+
+        // The mismatched update is skipped, and the unknown one is kept as
+        // the initial upload.
+        record_frame(&mut writer, 1, empty_display_list(), ResourceUpdates::new());
+        let frame = read_frame(&dir.join("frame-1.ron"));
+        assert_eq!(frame.images, vec![(key, descriptor(2)), (unknown_key, descriptor(2))]);
+    }
+
+    #[test]
+    fn streamed_images_are_written_through_the_output() {
+        let dir = test_dir("stream-resources");
+        let mut writer = RonFrameWriter::new(&dir);
+        writer.set_stream_resources(true);
+        let outputs = capture_outputs(&mut writer);
+
+        let mut updates = ResourceUpdates::new();
+        updates.add_image(image_key(1), descriptor(1), ImageData::new(vec![1; 4]), None);
+        updates.add_image(image_key(2), descriptor(1), ImageData::new(vec![2; 4]), None);
+        record_resources(&mut writer, updates);
+        // Updates rewrite the file.
+        let mut updates = ResourceUpdates::new();
+        updates.update_image(image_key(1), descriptor(1), ImageData::new(vec![3; 4]), None);
+        record_resources(&mut writer, updates);
+
+        let written: Vec<_> = outputs
+            .borrow()
+            .iter()
+            .map(|&(ref path, ref buffer)| (path.clone(), buffer.0.borrow().clone()))
+            .collect();
+        let res = dir.join("res");
+        assert_eq!(
+            written,
+            vec![
+                (res.join("image-0-1.bin"), vec![1; 4]),
+                (res.join("image-0-2.bin"), vec![2; 4]),
+                (res.join("image-0-1.bin"), vec![3; 4]),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_writes_are_reported() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("write-failure");
+        let mut writer = RonFrameWriter::new(&dir);
+        writer.set_stream_resources(true);
+        let errors = collect_errors(&mut writer);
+        writer.set_output(Box::new(|_| Err(io::Error::new(io::ErrorKind::Other, "disk full"))));
+
+        let mut resources = ResourceUpdates::new();
+        resources.add_image(image_key(1), descriptor(1), ImageData::new(vec![3; 4]), None);
+        record_frame(&mut writer, 1, empty_display_list(), resources);
+
+        let failed: Vec<_> = errors
+            .borrow()
+            .iter()
+            .map(|error| match *error {
+                WriterError::ImageWriteFailed { key, .. } => format!("image {}", key.1),
+                WriterError::WriteFailed { ref path, .. } => {
+                    path.file_name().unwrap().to_string_lossy().into_owned()
+                }
+                ref error => panic!("Unexpected error {:?}", error),
+            })
+            .collect();
+        assert_eq!(failed, vec!["image 1", "frame-1.ron", "manifest.ron"]);
+    }
+
+    #[test]
+    fn frame_numbering() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("frame-numbering");
+        let key = image_key(1);
+
+        // Resources of the frames outside the range are still tracked.
+        let mut writer = RonFrameWriter::new(&dir);
+        writer.set_frame_range(2, 2);
+        let mut resources = ResourceUpdates::new();
+        resources.add_image(key, descriptor(1), ImageData::new(vec![0; 4]), None);
+        record_frame(&mut writer, 1, empty_display_list(), resources);
+        assert!(!dir.join("frame-1.ron").exists());
+        record_frame(&mut writer, 2, empty_display_list(), ResourceUpdates::new());
+        assert_eq!(read_frame(&dir.join("frame-2.ron")).images, vec![(key, descriptor(1))]);
+        record_frame(&mut writer, 3, empty_display_list(), ResourceUpdates::new());
+        assert!(!dir.join("frame-3.ron").exists());
+
+        // A resumed capture is numbered after its last frame, the range
+        // refers to those numbers, and the images it knew are still known.
+        fs::File::create(dir.join("frame-9.png")).unwrap();
+        let mut writer = RonFrameWriter::open_existing(&dir);
+        let errors = collect_errors(&mut writer);
+        writer.set_frame_range(3, 4);
+        let mut resources = ResourceUpdates::new();
+        resources.update_image(key, descriptor(1), ImageData::new(vec![1; 4]), None);
+        record_frame(&mut writer, 1, empty_display_list(), resources);
+        record_frame(&mut writer, 2, empty_display_list(), ResourceUpdates::new());
+        assert!(errors.borrow().is_empty());
+        assert_eq!(read_frame(&dir.join("frame-3.ron")).images, vec![(key, descriptor(1))]);
+        assert!(dir.join("frame-4.ron").exists());
+    }
+
+    #[test]
+    fn normalized_epochs_increase() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("normalized-epochs");
+        let mut writer = RonFrameWriter::new(&dir);
+        writer.set_normalize_epochs(true);
+
+        let epochs: Vec<u32> = [5, 3, 3, 8, 2]
+            .iter()
+            .enumerate()
+            .map(|(index, &epoch)| {
+                let frame = index as u32 + 1;
+                let dl = empty_display_list();
+                let (msg, payload) = display_list_msg(frame, Epoch(epoch), dl, ResourceUpdates::new());
+                writer.write_msg(frame, &msg);
+                writer.write_payload(frame, &payload);
+                read_frame(&dir.join(format!("frame-{}.ron", frame))).epoch.0
+            })
+            .collect();
+        assert_eq!(epochs, vec![5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn optional_outputs_are_written() {
+        let _lock = FRAME_LOCK.lock().unwrap();
+        let dir = test_dir("optional-outputs");
+        let mut writer = RonFrameWriter::new(&dir);
+        writer.set_write_thumbnails(true);
+        writer.set_write_trees(true);
+        let outputs = capture_outputs(&mut writer);
+        let key = image_key(1);
+
+        record_frame(&mut writer, 1, empty_display_list(), ResourceUpdates::new());
+
+        let push_stacking_context = |builder: &mut DisplayListBuilder| {
+            builder.push_stacking_context(
+                &info(10.0),
+                ScrollPolicy::Scrollable,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                Vec::new(),
+            );
+        };
+        let dl = build_display_list(|builder| {
+            push_stacking_context(builder);
+            push_image(builder, 2.0, ImageRendering::Auto, key);
+            push_stacking_context(builder);
+            builder.push_rect(&info(10.0), ColorF::new(1.0, 0.0, 0.0, 1.0));
+            builder.pop_stacking_context();
+            builder.pop_stacking_context();
+        });
+        let tree = StackingContextNode::from_display_list(&dl, LayoutSize::new(100.0, 100.0));
+        assert_eq!(tree.depth(), 2);
+        assert_eq!(tree.children[0].item_count, 1);
+        assert_eq!(tree.children[0].children[0].item_count, 1);
+
+        let mut resources = ResourceUpdates::new();
+        resources.add_image(key, descriptor(2), ImageData::new(vec![0xff; 16]), None);
+        record_frame(&mut writer, 2, dl, resources);
+
+        // The manifest is written once there is something to record, which
+        // here is the first paint along with the image.
+        let outputs = outputs.borrow();
+        let paths: Vec<_> = outputs.iter().map(|output| output.0.clone()).collect();
+        let expected = [
+            "frame-1.ron",
+            "frame-1.tree.ron",
+            "frame-2.ron",
+            "manifest.ron",
+            "frame-2.tree.ron",
+            "frame-2.png",
+        ];
+        assert_eq!(paths, expected.iter().map(|name| dir.join(name)).collect::<Vec<_>>());
+
+        let manifest = String::from_utf8(outputs[3].1 .0.borrow().clone()).unwrap();
+        let manifest: CaptureManifest = ron::de::from_str(&manifest).unwrap();
+        assert_eq!(manifest.first_paint, Some(2));
+        assert_eq!(manifest.images, vec![(key, descriptor(2))]);
+        let thumbnail = image::load_from_memory(&outputs[5].1 .0.borrow()).unwrap();
+        assert_eq!(thumbnail.dimensions(), (2, 2));
     }
 }
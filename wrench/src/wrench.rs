@@ -12,7 +12,7 @@ use dwrote;
 use font_loader::system_fonts;
 use glutin::WindowProxy;
 use json_frame_writer::JsonFrameWriter;
-use ron_frame_writer::RonFrameWriter;
+use ron_frame_writer::{RonFrameWriter, RonOptions};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
@@ -41,7 +41,7 @@ pub enum FontDescriptor {
 pub enum SaveType {
     Yaml,
     Json,
-    Ron,
+    Ron(RonOptions),
     Binary,
 }
 
@@ -164,8 +164,9 @@ impl Wrench {
             ) as Box<webrender::ApiRecordingReceiver>,
             SaveType::Json => Box::new(JsonFrameWriter::new(&PathBuf::from("json_frames"))) as
                 Box<webrender::ApiRecordingReceiver>,
-            SaveType::Ron => Box::new(RonFrameWriter::new(&PathBuf::from("ron_frames"))) as
-                Box<webrender::ApiRecordingReceiver>,
+            SaveType::Ron(options) => Box::new(
+                RonFrameWriter::with_options(&PathBuf::from("ron_frames"), &options),
+            ) as Box<webrender::ApiRecordingReceiver>,
             SaveType::Binary => Box::new(webrender::BinaryRecorder::new(
                 &PathBuf::from("wr-record.bin"),
             )) as Box<webrender::ApiRecordingReceiver>,